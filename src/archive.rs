@@ -0,0 +1,150 @@
+use crate::filter::Filter;
+use crate::hasher::HasherFactory;
+use crate::{
+    compute_full_hash, distinct_roots, walk_root, DuplicateGroup, Hash, ScanOptions, HASH_BUFLEN,
+};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// Duplicates among the entries of one archive. The paths of the groups are
+/// the names of the entries within the archive.
+#[derive(Debug)]
+pub struct ArchiveDuplicates {
+    pub archive: PathBuf,
+    pub groups: Vec<DuplicateGroup>,
+}
+
+enum Kind {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+fn archive_kind(path: &Path) -> Option<Kind> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    if name.ends_with(".tar") {
+        Some(Kind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(Kind::TarGz)
+    } else if name.ends_with(".zip") {
+        Some(Kind::Zip)
+    } else {
+        None
+    }
+}
+
+// Collects entries by size and hash, in archive order
+#[derive(Default)]
+struct Grouper {
+    groups: Vec<DuplicateGroup>,
+    group_of: HashMap<(u64, Hash), usize>,
+}
+
+impl Grouper {
+    fn add(&mut self, name: PathBuf, entry: impl Read) -> io::Result<()> {
+        let (hash, size) = compute_full_hash(entry, HASH_BUFLEN, &HasherFactory::default())?;
+        match self.group_of.get(&(size, hash)) {
+            Some(&i) => self.groups[i].paths.push(name),
+            None => {
+                self.group_of.insert((size, hash), self.groups.len());
+                self.groups.push(DuplicateGroup {
+                    size,
+                    hash,
+                    paths: vec![name],
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn into_duplicates(mut self) -> Vec<DuplicateGroup> {
+        self.groups.retain(|group| group.paths.len() > 1);
+        self.groups
+    }
+}
+
+fn tar_duplicates(reader: impl Read) -> io::Result<Vec<DuplicateGroup>> {
+    let mut grouper = Grouper::default();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file() && entry.size() > 0 {
+            let name = entry.path()?.into_owned();
+            grouper.add(name, entry)?;
+        }
+    }
+    Ok(grouper.into_duplicates())
+}
+
+fn zip_duplicates(file: File) -> io::Result<Vec<DuplicateGroup>> {
+    let mut grouper = Grouper::default();
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        if entry.is_file() && entry.size() > 0 {
+            let name = PathBuf::from(entry.name());
+            grouper.add(name, entry)?;
+        }
+    }
+    Ok(grouper.into_duplicates())
+}
+
+/// Finds duplicate entries within the tar archive (optionally gzipped) or
+/// zip archive at `path`. Returns `None` if `path` is not a recognized
+/// archive.
+pub fn duplicates_in_archive(path: &Path) -> io::Result<Option<Vec<DuplicateGroup>>> {
+    let Some(kind) = archive_kind(path) else {
+        return Ok(None);
+    };
+    let file = File::open(path)?;
+    let groups = match kind {
+        Kind::Tar => tar_duplicates(BufReader::new(file))?,
+        Kind::TarGz => tar_duplicates(flate2::read::MultiGzDecoder::new(BufReader::new(file)))?,
+        Kind::Zip => zip_duplicates(file)?,
+    };
+    Ok(Some(groups))
+}
+
+/// Walks `paths` and finds duplicate entries within every tar or zip archive
+/// found. Archives without duplicates are left out. The extension and size
+/// filters of `options` apply to the archives, not to their entries.
+pub fn find_archive_duplicates(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+) -> io::Result<Vec<ArchiveDuplicates>> {
+    let filter = Filter::new(options)?;
+    let mut archives = Vec::new();
+    for root in &distinct_roots(paths) {
+        for entry in walk_root(root, options, &filter, &mut 0)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() || !filter.has_wanted_extension(entry.path()) {
+                continue;
+            }
+            match entry.metadata() {
+                Ok(metadata) if metadata.len() > filter.min_size(entry.path()) => {}
+                Ok(_) => continue,
+                Err(err) => {
+                    options.on_error.handle(entry.path(), err.into())?;
+                    continue;
+                }
+            }
+            match duplicates_in_archive(entry.path()) {
+                Ok(Some(groups)) if !groups.is_empty() => archives.push(ArchiveDuplicates {
+                    archive: entry.into_path(),
+                    groups,
+                }),
+                Ok(_) => {}
+                Err(err) => options.on_error.handle(entry.path(), err)?,
+            }
+        }
+    }
+    Ok(archives)
+}
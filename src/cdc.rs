@@ -0,0 +1,115 @@
+use crate::filter::Filter;
+use crate::hasher::{self, HasherFactory};
+use crate::{walk_root, Hash, ScanOptions};
+use fastcdc::v2020::StreamCDC;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+// Chunk size bounds, as typically used by block-level deduplicating backup
+// tools and file systems
+const MIN_CHUNK_LEN: u32 = 16 * 1024;
+const AVG_CHUNK_LEN: u32 = 64 * 1024;
+const MAX_CHUNK_LEN: u32 = 256 * 1024;
+
+/// How much of the data a block-level deduplicating store could share,
+/// compared to deduplicating whole files.
+#[derive(Debug, Default)]
+pub struct ChunkEstimate {
+    pub num_files: u64,
+    pub total_bytes: u64,
+    pub num_chunks: u64,
+    pub unique_chunks: u64,
+    /// Bytes in chunks that occurred before, in the same or another file.
+    pub shared_chunk_bytes: u64,
+    /// Bytes in files identical to a file that occurred before.
+    pub duplicate_file_bytes: u64,
+}
+
+/// Walks `paths` and splits every file into content-defined chunks
+/// (FastCDC, 64 KiB on average), counting the bytes in chunks seen before.
+/// Every file is read in full, once; its whole-file hash is computed along
+/// the way for comparison with file-level deduplication. Keeps a hash of
+/// every distinct chunk in memory, about 50 bytes per 64 KiB of data.
+pub fn estimate_chunk_savings(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+) -> io::Result<ChunkEstimate> {
+    let filter = Filter::new(options)?;
+    let factory = HasherFactory::default();
+    let mut chunks: HashSet<Hash> = HashSet::new();
+    let mut files: HashSet<(u64, Hash)> = HashSet::new();
+    let mut estimate = ChunkEstimate::default();
+    for root in paths {
+        for entry in walk_root(root, options, &filter, &mut 0)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() || !filter.has_wanted_extension(entry.path()) {
+                continue;
+            }
+            let size = match entry.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(err) => {
+                    options.on_error.handle(entry.path(), err.into())?;
+                    continue;
+                }
+            };
+            if size == 0 || size <= filter.min_size(entry.path()) {
+                continue;
+            }
+
+            let file = match File::open(entry.path()) {
+                Ok(file) => file,
+                Err(err) => {
+                    options.on_error.handle(entry.path(), err)?;
+                    continue;
+                }
+            };
+            let mut file_hasher = factory.hasher();
+            let mut file_chunks = Vec::new();
+            let mut read_error = None;
+            for chunk in StreamCDC::new(file, MIN_CHUNK_LEN, AVG_CHUNK_LEN, MAX_CHUNK_LEN) {
+                match chunk {
+                    Ok(chunk) => {
+                        file_hasher.update(&chunk.data);
+                        file_chunks.push((factory.hash_bytes(&chunk.data), chunk.length));
+                    }
+                    Err(fastcdc::v2020::Error::IoError(err)) => {
+                        read_error = Some(err);
+                        break;
+                    }
+                    Err(err) => {
+                        read_error = Some(io::Error::other(err.to_string()));
+                        break;
+                    }
+                }
+            }
+            // Only count files that were read in full
+            if let Some(err) = read_error {
+                options.on_error.handle(entry.path(), err)?;
+                continue;
+            }
+
+            estimate.num_files += 1;
+            estimate.total_bytes += size;
+            for (hash, len) in file_chunks {
+                estimate.num_chunks += 1;
+                if chunks.insert(hash) {
+                    estimate.unique_chunks += 1;
+                } else {
+                    estimate.shared_chunk_bytes += len as u64;
+                }
+            }
+            if !files.insert((size, hasher::finalize(file_hasher))) {
+                estimate.duplicate_file_bytes += size;
+            }
+        }
+    }
+    Ok(estimate)
+}
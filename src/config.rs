@@ -0,0 +1,158 @@
+use crate::Options;
+use anyhow::Context;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command, CommandFactory};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_CONFIG_FILE: &str = ".dedup.toml";
+
+// The config file in the platform's config directory: $XDG_CONFIG_HOME/dedup
+// (~/.config/dedup) on Linux, ~/Library/Application Support/dedup on macOS
+fn user_config_file() -> Option<PathBuf> {
+    let dirs = directories::ProjectDirs::from("", "", "dedup")?;
+    Some(dirs.config_dir().join("config.toml"))
+}
+
+/// The file that a bare `--hash-cache` uses, in the platform's cache
+/// directory: $XDG_CACHE_HOME/dedup (~/.cache/dedup) on Linux,
+/// ~/Library/Caches/dedup on macOS.
+pub fn default_hash_cache() -> Option<PathBuf> {
+    let dirs = directories::ProjectDirs::from("", "", "dedup")?;
+    Some(dirs.cache_dir().join("hashes"))
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    Report,
+    Symlink,
+    Remove,
+}
+
+// Whether an option given on the command line conflicts with the option
+// `id`, in either direction, so that the config file must not set it either
+fn conflicts_with_command_line(command: &Command, matches: &ArgMatches, id: &str) -> bool {
+    let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == id) else {
+        return false;
+    };
+    matches
+        .ids()
+        .filter(|given| matches.value_source(given.as_str()) == Some(ValueSource::CommandLine))
+        .filter_map(|given| {
+            command
+                .get_arguments()
+                .find(|other| other.get_id() == given)
+        })
+        .any(|given| {
+            let conflict = |a, b: &clap::Arg| {
+                command
+                    .get_arg_conflicts_with(a)
+                    .iter()
+                    .any(|other| other.get_id() == b.get_id())
+            };
+            conflict(given, arg) || conflict(arg, given)
+        })
+}
+
+/// Default options read from a TOML file. Every field is optional; options
+/// given on the command line take precedence over the file.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    min_size: Option<u64>,
+    max_depth: Option<usize>,
+    max_files: Option<u64>,
+    ext: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    case_sensitive: Option<bool>,
+    ignore_trailing: Option<u64>,
+    one_file_system: Option<bool>,
+    verbose: Option<bool>,
+    mode: Option<Mode>,
+    adaptive_buffer: Option<bool>,
+    stats: Option<bool>,
+}
+
+impl Config {
+    /// Reads the config file at `path`. If no path is given, reads
+    /// `.dedup.toml` in the working directory or, failing that, `config.toml`
+    /// in the user's config directory, if either exists.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Option<Config>> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let candidates = [Some(PathBuf::from(DEFAULT_CONFIG_FILE)), user_config_file()];
+                match candidates.into_iter().flatten().find(|path| path.exists()) {
+                    Some(path) => path,
+                    None => return Ok(None),
+                }
+            }
+        };
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        let config = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {:?}", path))?;
+        Ok(Some(config))
+    }
+
+    /// Fills in every option in `options` that was not given on the command
+    /// line and is set in this config, unless an option given on the command
+    /// line conflicts with it: `mode = "remove"` does not apply with a report
+    /// only option such as `--normalize-eol`.
+    pub fn apply(&self, options: &mut Options, matches: &ArgMatches) {
+        let mut command = Options::command();
+        // Registers the groups, such as `mode`, that conflicts refer to
+        command.build();
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        let unset = |id: &str| !given(id) && !conflicts_with_command_line(&command, matches, id);
+
+        if let Some(min_size) = self.min_size.filter(|_| unset("min_size")) {
+            options.min_size = min_size;
+        }
+        if let Some(max_depth) = self.max_depth.filter(|_| unset("max_depth")) {
+            options.max_depth = Some(max_depth);
+        }
+        if let Some(max_files) = self.max_files.filter(|_| unset("max_files")) {
+            options.max_files = Some(max_files);
+        }
+        if let Some(ext) = self.ext.as_ref().filter(|_| unset("extensions")) {
+            options.extensions = ext.clone();
+        }
+        if let Some(exclude) = self.exclude.as_ref().filter(|_| unset("exclude")) {
+            options.exclude = exclude.clone();
+        }
+        if let Some(case_sensitive) = self.case_sensitive.filter(|_| unset("case_sensitive")) {
+            options.case_sensitive = case_sensitive;
+        }
+        if let Some(ignore_trailing) = self.ignore_trailing.filter(|_| unset("ignore_trailing")) {
+            options.ignore_trailing = ignore_trailing;
+        }
+        if let Some(one_file_system) = self.one_file_system.filter(|_| unset("one_file_system")) {
+            options.one_file_system = one_file_system;
+        }
+        if let Some(verbose) = self.verbose.filter(|_| unset("verbose")) {
+            options.verbose = verbose;
+        }
+        let mode_allowed = |mode: &Mode| {
+            !given("replace_by_symlink")
+                && !given("remove")
+                && match mode {
+                    Mode::Report => true,
+                    Mode::Symlink => unset("replace_by_symlink"),
+                    Mode::Remove => unset("remove"),
+                }
+        };
+        if let Some(mode) = self.mode.filter(mode_allowed) {
+            options.replace_by_symlink = matches!(mode, Mode::Symlink);
+            options.remove = matches!(mode, Mode::Remove);
+        }
+        if let Some(adaptive_buffer) = self.adaptive_buffer.filter(|_| unset("adaptive_buffer")) {
+            options.adaptive_buffer = adaptive_buffer;
+        }
+        if let Some(stats) = self.stats.filter(|_| unset("stats")) {
+            options.stats = stats;
+        }
+    }
+}
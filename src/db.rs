@@ -0,0 +1,83 @@
+use rusqlite::{params, Connection};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Version of the schema below, stored as the database's `user_version`.
+/// Columns are only ever added; a change to existing ones bumps the version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA: &str = "
+CREATE TABLE groups (
+    id INTEGER PRIMARY KEY,
+    size INTEGER NOT NULL,
+    hash TEXT NOT NULL
+);
+CREATE TABLE files (
+    id INTEGER PRIMARY KEY,
+    group_id INTEGER NOT NULL REFERENCES groups (id),
+    path TEXT NOT NULL,
+    dir TEXT NOT NULL,
+    extension TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    kept INTEGER NOT NULL
+);
+CREATE INDEX files_group ON files (group_id);
+CREATE INDEX files_dir ON files (dir);
+";
+
+/// A file of a group, as stored in the `files` table.
+pub struct File {
+    pub path: String,
+    pub dir: String,
+    pub extension: String,
+    pub kept: bool,
+}
+
+pub struct Group {
+    pub size: u64,
+    pub hash: String,
+    pub files: Vec<File>,
+}
+
+/// Writes the groups to a new SQLite database at `path`, replacing any file
+/// there. As with the metrics file, the database is written next to `path`
+/// and then renamed, so that it is never seen half written.
+pub fn write(path: &Path, groups: &[Group]) -> anyhow::Result<()> {
+    let mut tmp_path = OsString::from(path.as_os_str());
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let _ = fs::remove_file(&tmp_path);
+
+    let mut conn = Connection::open(&tmp_path)?;
+    conn.execute_batch(SCHEMA)?;
+    conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    let tx = conn.transaction()?;
+    {
+        let mut insert_group = tx.prepare("INSERT INTO groups (size, hash) VALUES (?1, ?2)")?;
+        let mut insert_file = tx.prepare(
+            "INSERT INTO files (group_id, path, dir, extension, size, hash, kept)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        for group in groups {
+            insert_group.execute(params![group.size, group.hash])?;
+            let group_id = tx.last_insert_rowid();
+            for file in &group.files {
+                insert_file.execute(params![
+                    group_id,
+                    file.path,
+                    file.dir,
+                    file.extension,
+                    group.size,
+                    group.hash,
+                    file.kept
+                ])?;
+            }
+        }
+    }
+    tx.commit()?;
+    conn.close().map_err(|(_, err)| err)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// A gzip member is at least a 10 byte header and an 8 byte trailer
+const MIN_GZIP_LEN: u64 = 18;
+
+pub(crate) fn is_compressed(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz") || ext.eq_ignore_ascii_case("tgz"))
+}
+
+// Reads the uncompressed size (modulo 2^32) from the trailer of the last
+// gzip member. Only a hint: with several members, the others are not
+// counted. Files too short to be gzip give None.
+pub(crate) fn trailer_size(path: &Path) -> io::Result<Option<u32>> {
+    let mut file = File::open(path)?;
+    if file.metadata()?.len() < MIN_GZIP_LEN {
+        return Ok(None);
+    }
+    let mut isize = [0u8; 4];
+    file.seek(SeekFrom::End(-4))?;
+    file.read_exact(&mut isize)?;
+    Ok(Some(u32::from_le_bytes(isize)))
+}
+
+// Returns the length of the decompressed contents of all gzip members. This
+// takes decompressing the whole file, as the trailer cannot be trusted; see
+// `trailer_size`. Files that are not valid gzip give None, so that they are
+// compared by their raw bytes.
+pub(crate) fn decompressed_size(path: &Path) -> io::Result<Option<u64>> {
+    let mut decoder = MultiGzDecoder::new(File::open(path)?);
+    match io::copy(&mut decoder, &mut io::sink()) {
+        Ok(len) => Ok(Some(len)),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::InvalidInput
+                    | io::ErrorKind::InvalidData
+                    | io::ErrorKind::UnexpectedEof
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,118 @@
+use crate::filter::Filter;
+use crate::{walk_root, Contents, Hash, HashCache, ScanOptions};
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Comparison of the contents of two directory trees, ignoring file names.
+#[derive(Debug, Default)]
+pub struct TreeDiff {
+    /// Files in the left tree whose contents occur nowhere in the right tree.
+    pub left_only: Vec<PathBuf>,
+    /// Files in the right tree whose contents occur nowhere in the left tree.
+    pub right_only: Vec<PathBuf>,
+    /// Files in the left tree whose contents also occur in the right tree.
+    pub shared: Vec<PathBuf>,
+    pub left_only_bytes: u64,
+    pub right_only_bytes: u64,
+    pub shared_bytes: u64,
+}
+
+fn collect_files(
+    root: &Path,
+    options: &ScanOptions,
+    filter: &Filter,
+) -> io::Result<BTreeMap<u64, Vec<PathBuf>>> {
+    let mut files: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    for entry in walk_root(root, options, filter, &mut 0)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() || !filter.has_wanted_extension(entry.path()) {
+            continue;
+        }
+        match entry.metadata() {
+            Ok(metadata) if metadata.len() > filter.min_size(entry.path()) => {
+                files
+                    .entry(metadata.len())
+                    .or_default()
+                    .push(entry.into_path());
+            }
+            Ok(_) => {}
+            Err(err) => options.on_error.handle(entry.path(), err.into())?,
+        }
+    }
+    Ok(files)
+}
+
+// Hashes every file in `paths`, leaving out files that cannot be read.
+fn hash_files(
+    paths: &[PathBuf],
+    size: u64,
+    hashes: &mut HashCache,
+    options: &ScanOptions,
+) -> io::Result<Vec<(Hash, PathBuf)>> {
+    let mut hashed = Vec::with_capacity(paths.len());
+    for path in paths {
+        match hashes.full_hash(path, size) {
+            Ok(hash) => hashed.push((hash, path.clone())),
+            Err(err) => options.on_error.handle(path, err)?,
+        }
+    }
+    Ok(hashed)
+}
+
+/// Compares the files below `left` and `right` by content. Files are only
+/// hashed if the other tree has a file of the same size.
+pub fn diff_trees(left: &Path, right: &Path, options: &ScanOptions) -> io::Result<TreeDiff> {
+    let filter = Filter::new(options)?;
+    let left_files = collect_files(left, options, &filter)?;
+    let right_files = collect_files(right, options, &filter)?;
+    let mut hashes = HashCache::new(options, Contents::Raw);
+    let mut diff = TreeDiff::default();
+
+    for (&size, paths) in &left_files {
+        let Some(other_paths) = right_files.get(&size) else {
+            diff.left_only_bytes += size * paths.len() as u64;
+            diff.left_only.extend(paths.iter().cloned());
+            continue;
+        };
+        let other: HashSet<Hash> = hash_files(other_paths, size, &mut hashes, options)?
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .collect();
+        for (hash, path) in hash_files(paths, size, &mut hashes, options)? {
+            if other.contains(&hash) {
+                diff.shared_bytes += size;
+                diff.shared.push(path);
+            } else {
+                diff.left_only_bytes += size;
+                diff.left_only.push(path);
+            }
+        }
+    }
+
+    for (&size, paths) in &right_files {
+        let Some(other_paths) = left_files.get(&size) else {
+            diff.right_only_bytes += size * paths.len() as u64;
+            diff.right_only.extend(paths.iter().cloned());
+            continue;
+        };
+        let other: HashSet<Hash> = hash_files(other_paths, size, &mut hashes, options)?
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .collect();
+        for (hash, path) in hash_files(paths, size, &mut hashes, options)? {
+            if !other.contains(&hash) {
+                diff.right_only_bytes += size;
+                diff.right_only.push(path);
+            }
+        }
+    }
+
+    Ok(diff)
+}
@@ -0,0 +1,255 @@
+use crate::filter::Filter;
+use crate::{distinct_roots, walk_root, Contents, Hash, HashCache, ScanOptions};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// A set of directories whose entire contents are identical.
+#[derive(Debug)]
+pub struct DuplicateDirGroup {
+    /// Total size of the files in each of the directories.
+    pub size: u64,
+    pub hash: Hash,
+    pub paths: Vec<PathBuf>,
+}
+
+struct DirSummary {
+    path: PathBuf,
+    hash: Hash,
+    size: u64,
+}
+
+// A directory whose entries are still being walked
+struct OpenDir {
+    path: PathBuf,
+    // Hasher and total size of the entries seen so far
+    hasher: Sha256,
+    size: u64,
+    // Whether every entry below it could be read
+    complete: bool,
+}
+
+impl OpenDir {
+    fn add(&mut self, name: &OsStr, tag: u8, hash: &[u8], size: u64) {
+        self.hasher.update(name.as_bytes());
+        self.hasher.update([0, tag]);
+        self.hasher.update(hash);
+        self.size += size;
+    }
+}
+
+// Finishes the innermost open directory, adding it to its parent and, if it
+// is complete and not empty, to `dirs`
+fn close_dir(stack: &mut Vec<OpenDir>, dirs: &mut Vec<DirSummary>) {
+    let Some(dir) = stack.pop() else {
+        return;
+    };
+    let hash = dir.hasher.finalize();
+    if let Some(parent) = stack.last_mut() {
+        parent.add(
+            dir.path.file_name().unwrap_or_default(),
+            b'd',
+            &hash,
+            dir.size,
+        );
+        parent.complete &= dir.complete;
+    }
+    if dir.complete && dir.size > 0 {
+        dirs.push(DirSummary {
+            path: dir.path,
+            hash,
+            size: dir.size,
+        });
+    }
+}
+
+// Computes a Merkle-style hash for every non-empty directory below `root`,
+// combining the name, kind and hash of each entry. Files are hashed by
+// `leaf_hash`, symlinks by their target. Directories with an entry that
+// could not be read are left out, as are their parents. Errors listing the
+// tree are passed to `on_error` only if `report_errors` is set, so that a
+// second walk of the same tree does not report them again; errors returned by
+// `leaf_hash` always are.
+fn hash_tree<F>(
+    root: &Path,
+    options: &ScanOptions,
+    filter: &Filter,
+    report_errors: bool,
+    mut leaf_hash: F,
+) -> io::Result<Vec<DirSummary>>
+where
+    F: FnMut(&Path, u64) -> io::Result<Hash>,
+{
+    let mut dirs = Vec::new();
+    // The directories from `root` down to the one being walked
+    let mut stack: Vec<OpenDir> = Vec::new();
+
+    for entry in walk_root(root, options, filter, &mut 0)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                let path = err.path().unwrap_or(root).to_path_buf();
+                if let Some(dir) = stack
+                    .iter_mut()
+                    .rev()
+                    .find(|dir| path.starts_with(&dir.path))
+                {
+                    dir.complete = false;
+                }
+                if report_errors {
+                    options.on_error.handle(&path, err.into())?;
+                }
+                continue;
+            }
+        };
+        while stack.len() > entry.depth() {
+            close_dir(&mut stack, &mut dirs);
+        }
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            stack.push(OpenDir {
+                path: entry.into_path(),
+                hasher: Sha256::new(),
+                size: 0,
+                complete: true,
+            });
+            continue;
+        }
+        let Some(parent) = stack.last_mut() else {
+            continue;
+        };
+        // Files are hashed by `leaf_hash` once they are known to be wanted;
+        // errors before that are errors listing the tree
+        let listed = if file_type.is_file() {
+            entry
+                .metadata()
+                .map(|metadata| (b'f', None, metadata.len()))
+                .map_err(io::Error::from)
+        } else if file_type.is_symlink() {
+            fs::read_link(entry.path())
+                .map(|target| (b'l', Some(Sha256::digest(target.as_os_str().as_bytes())), 0))
+        } else {
+            Ok((b'o', Some(Hash::default()), 0))
+        };
+        let (tag, hash, size) = match listed {
+            Ok(listed) => listed,
+            Err(err) => {
+                parent.complete = false;
+                if report_errors {
+                    options.on_error.handle(entry.path(), err)?;
+                }
+                continue;
+            }
+        };
+        let hash = match hash {
+            Some(hash) => hash,
+            None if !filter.has_wanted_extension(entry.path())
+                || size <= filter.min_size(entry.path()) =>
+            {
+                continue
+            }
+            None => match leaf_hash(entry.path(), size) {
+                Ok(hash) => hash,
+                Err(err) => {
+                    parent.complete = false;
+                    options.on_error.handle(entry.path(), err)?;
+                    continue;
+                }
+            },
+        };
+        parent.add(entry.file_name(), tag, &hash, size);
+    }
+    while !stack.is_empty() {
+        close_dir(&mut stack, &mut dirs);
+    }
+
+    Ok(dirs)
+}
+
+fn group_by_hash(dirs: Vec<DirSummary>) -> Vec<Vec<DirSummary>> {
+    let mut groups: Vec<Vec<DirSummary>> = Vec::new();
+    let mut group_of: HashMap<Hash, usize> = HashMap::new();
+    for dir in dirs {
+        match group_of.get(&dir.hash) {
+            Some(&i) => groups[i].push(dir),
+            None => {
+                group_of.insert(dir.hash, groups.len());
+                groups.push(vec![dir]);
+            }
+        }
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+/// Walks `paths` and collects groups of directories with identical contents,
+/// i.e. the same file names, directory structure and file contents.
+///
+/// Directories are first compared by the names and sizes of their contents;
+/// only files in directories that match on that are hashed. Groups whose
+/// members are all contained in other duplicate directories are omitted.
+/// Files and directories left out by `options` do not count towards the
+/// contents of a directory, nor does anything below `max_depth`.
+pub fn find_duplicate_dirs(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+) -> io::Result<Vec<DuplicateDirGroup>> {
+    let paths = &distinct_roots(paths);
+    let filter = Filter::new(options)?;
+    let mut shapes = Vec::new();
+    for root in paths {
+        shapes.extend(hash_tree(root, options, &filter, true, |_, size| {
+            Ok(Sha256::digest(size.to_le_bytes()))
+        })?);
+    }
+    let matching_shapes: HashSet<PathBuf> = group_by_hash(shapes)
+        .into_iter()
+        .flatten()
+        .map(|dir| dir.path)
+        .collect();
+
+    // Only the directories that matched on their shape are kept, so only
+    // files below them need to be read
+    let mut hashes = HashCache::new(options, Contents::Raw);
+    let mut candidates = Vec::new();
+    for root in paths {
+        let tree = hash_tree(root, options, &filter, false, |path, size| {
+            if path.ancestors().any(|dir| matching_shapes.contains(dir)) {
+                hashes.full_hash(path, size)
+            } else {
+                Ok(Sha256::digest(size.to_le_bytes()))
+            }
+        })?;
+        candidates.extend(
+            tree.into_iter()
+                .filter(|dir| matching_shapes.contains(&dir.path)),
+        );
+    }
+
+    let groups = group_by_hash(candidates);
+    let duplicate_paths: HashSet<&Path> = groups
+        .iter()
+        .flatten()
+        .map(|dir| dir.path.as_path())
+        .collect();
+
+    Ok(groups
+        .iter()
+        .filter(|group| {
+            !group.iter().all(|dir| {
+                dir.path
+                    .parent()
+                    .is_some_and(|parent| duplicate_paths.contains(parent))
+            })
+        })
+        .map(|group| DuplicateDirGroup {
+            size: group[0].size,
+            hash: group[0].hash,
+            paths: group.iter().map(|dir| dir.path.clone()).collect(),
+        })
+        .collect())
+}
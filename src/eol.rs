@@ -0,0 +1,85 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+// Extensions of files that are compared as text by `Normalizer::normalize_eol`
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "rst", "csv", "tsv", "json", "xml", "html", "htm", "css", "svg", "yaml", "yml",
+    "toml", "ini", "cfg", "conf", "tex", "c", "h", "cc", "cpp", "cxx", "hpp", "cs", "java", "kt",
+    "go", "rs", "py", "rb", "pl", "php", "js", "ts", "jsx", "tsx", "sh", "bat", "cmd", "ps1",
+    "sql", "swift", "m",
+];
+
+pub(crate) fn is_text(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        TEXT_EXTENSIONS
+            .iter()
+            .any(|text| ext.eq_ignore_ascii_case(text))
+    })
+}
+
+// Reads `inner` with every CRLF line ending turned into LF. Lone CRs, as in
+// old Mac files, are left alone.
+pub(crate) struct CrlfToLf<R> {
+    inner: BufReader<R>,
+    // A CR ended the previous buffer, and whether it is dropped depends on
+    // the next byte
+    pending_cr: bool,
+}
+
+impl<R: Read> CrlfToLf<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        CrlfToLf {
+            inner: BufReader::new(inner),
+            pending_cr: false,
+        }
+    }
+}
+
+impl<R: Read> Read for CrlfToLf<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let buf = self.inner.fill_buf()?;
+            let mut written = 0;
+            if self.pending_cr {
+                self.pending_cr = false;
+                if buf.first() != Some(&b'\n') {
+                    out[0] = b'\r';
+                    written = 1;
+                }
+            }
+            if buf.is_empty() {
+                return Ok(written);
+            }
+            let mut used = 0;
+            while used < buf.len() && written < out.len() {
+                let byte = buf[used];
+                used += 1;
+                if byte == b'\r' {
+                    match buf.get(used) {
+                        Some(b'\n') => continue,
+                        Some(_) => {}
+                        None => {
+                            self.pending_cr = true;
+                            break;
+                        }
+                    }
+                }
+                out[written] = byte;
+                written += 1;
+            }
+            self.inner.consume(used);
+            // Only a CR at the end of the buffer was read, so read on
+            if written > 0 {
+                return Ok(written);
+            }
+        }
+    }
+}
+
+pub(crate) fn open_normalized(path: &Path) -> io::Result<Box<dyn Read>> {
+    Ok(Box::new(CrlfToLf::new(File::open(path)?)))
+}
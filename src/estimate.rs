@@ -0,0 +1,103 @@
+use crate::filter::Filter;
+use crate::{walk_root, Contents, Hash, HashCache, ScanOptions};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+
+// Number of files per size bucket that are hashed
+const FILES_PER_BUCKET: usize = 8;
+
+/// Estimate of the space that removing duplicates would free, from hashing
+/// the first and last blocks of a sample of the files.
+#[derive(Debug, Default)]
+pub struct Estimate {
+    pub num_files: u64,
+    /// Files that share their size with at least one other file, and could
+    /// therefore be duplicates.
+    pub candidate_files: u64,
+    pub candidate_bytes: u64,
+    /// Candidate files whose size bucket was sampled.
+    pub sampled_candidate_bytes: u64,
+    pub hashed_files: u64,
+    pub estimated_reclaimable: u64,
+}
+
+// Files of one size: the total count and the first few paths
+#[derive(Default)]
+struct Bucket {
+    count: u64,
+    paths: Vec<PathBuf>,
+}
+
+/// Walks `paths`, reading only metadata, and estimates the reclaimable space.
+/// Of the sizes shared by several files, up to `max_buckets` evenly spread
+/// over all of them are sampled. In each, a few files are compared by their
+/// first and last blocks, and the fraction found to be redundant is
+/// extrapolated to the whole bucket and then to all candidates.
+pub fn estimate_savings(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+    max_buckets: usize,
+) -> io::Result<Estimate> {
+    let filter = Filter::new(options)?;
+    let mut buckets: BTreeMap<u64, Bucket> = BTreeMap::new();
+    let mut estimate = Estimate::default();
+    for dir in paths {
+        for entry in walk_root(dir, options, &filter, &mut 0)?.flatten() {
+            if !entry.file_type().is_file() || !filter.has_wanted_extension(entry.path()) {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.len() <= filter.min_size(entry.path()) {
+                continue;
+            }
+            estimate.num_files += 1;
+            let bucket = buckets.entry(metadata.len()).or_default();
+            bucket.count += 1;
+            if bucket.paths.len() < FILES_PER_BUCKET {
+                bucket.paths.push(entry.into_path());
+            }
+        }
+    }
+
+    buckets.retain(|_, bucket| bucket.count > 1);
+    for (size, bucket) in &buckets {
+        estimate.candidate_files += bucket.count;
+        estimate.candidate_bytes += size * bucket.count;
+    }
+
+    let options = ScanOptions {
+        fast: true,
+        ..options.clone()
+    };
+    let mut hashes = HashCache::new(&options, Contents::Raw);
+    let stride = buckets.len().div_ceil(max_buckets.max(1)).max(1);
+    let mut sampled_reclaimable = 0.0;
+    for (&size, bucket) in buckets.iter().step_by(stride) {
+        let mut counts: HashMap<Hash, u64> = HashMap::new();
+        for path in &bucket.paths {
+            match hashes.full_hash(path, size) {
+                Ok(hash) => *counts.entry(hash).or_default() += 1,
+                Err(err) => options.on_error.handle(path, err)?,
+            }
+            hashes.forget(path);
+        }
+        let hashed: u64 = counts.values().sum();
+        if hashed == 0 {
+            continue;
+        }
+        let redundant = hashed - counts.len() as u64;
+        estimate.hashed_files += hashed;
+        estimate.sampled_candidate_bytes += size * bucket.count;
+        sampled_reclaimable += (size * bucket.count) as f64 * redundant as f64 / hashed as f64;
+    }
+
+    if estimate.sampled_candidate_bytes > 0 {
+        estimate.estimated_reclaimable = (sampled_reclaimable
+            / estimate.sampled_candidate_bytes as f64
+            * estimate.candidate_bytes as f64) as u64;
+    }
+    Ok(estimate)
+}
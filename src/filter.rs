@@ -0,0 +1,125 @@
+use crate::ScanOptions;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Decides which paths take part in a scan, based on `ScanOptions::extensions`
+// and `ScanOptions::exclude`, and the minimum size of each file. Extensions and
+// globs are matched case-insensitively unless `ScanOptions::case_sensitive` is
+// set. Files in `ScanOptions::skip_files` are always excluded.
+pub(crate) struct Filter {
+    extensions: Vec<String>,
+    min_size: u64,
+    min_size_for: Vec<(String, u64)>,
+    exclude: GlobSet,
+    case_sensitive: bool,
+    // File name and resolved parent directory of each file to skip
+    skip_files: Vec<(OsString, PathBuf)>,
+}
+
+// Resolves the directory containing `path`, which need not exist itself
+fn resolved_parent(path: &Path) -> Option<PathBuf> {
+    match path.parent()? {
+        dir if dir.as_os_str().is_empty() => Path::new(".").canonicalize().ok(),
+        dir => dir.canonicalize().ok(),
+    }
+}
+
+impl Filter {
+    pub(crate) fn new(options: &ScanOptions) -> io::Result<Filter> {
+        let mut exclude = GlobSetBuilder::new();
+        for pattern in &options.exclude {
+            let glob = GlobBuilder::new(pattern)
+                .case_insensitive(!options.case_sensitive)
+                .build()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            exclude.add(glob);
+        }
+        let exclude = exclude
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        let normalize = |ext: &str| {
+            let ext = ext.trim_start_matches('.');
+            if options.case_sensitive {
+                ext.to_string()
+            } else {
+                ext.to_lowercase()
+            }
+        };
+        let extensions = options
+            .extensions
+            .iter()
+            .map(|ext| normalize(ext))
+            .collect();
+        let min_size_for = options
+            .min_size_for
+            .iter()
+            .map(|(ext, min_size)| (normalize(ext), *min_size))
+            .collect();
+
+        let skip_files = options
+            .skip_files
+            .iter()
+            .filter_map(|path| Some((path.file_name()?.to_owned(), resolved_parent(path)?)))
+            .collect();
+
+        Ok(Filter {
+            extensions,
+            min_size: options.min_size,
+            min_size_for,
+            exclude,
+            case_sensitive: options.case_sensitive,
+            skip_files,
+        })
+    }
+
+    pub(crate) fn is_excluded(&self, path: &Path) -> bool {
+        self.exclude.is_match(path) || self.is_skipped_file(path)
+    }
+
+    fn is_skipped_file(&self, path: &Path) -> bool {
+        // Only resolve the parent of files with a matching name
+        let Some(name) = path.file_name() else {
+            return false;
+        };
+        if !self.skip_files.iter().any(|(skipped, _)| skipped == name) {
+            return false;
+        }
+        let parent = resolved_parent(path);
+        self.skip_files
+            .iter()
+            .any(|(skipped, dir)| skipped == name && parent.as_ref() == Some(dir))
+    }
+
+    pub(crate) fn has_wanted_extension(&self, path: &Path) -> bool {
+        if self.extensions.is_empty() {
+            return true;
+        }
+        self.extension(path)
+            .is_some_and(|ext| self.extensions.contains(&ext))
+    }
+
+    // Files must be larger than this to take part
+    pub(crate) fn min_size(&self, path: &Path) -> u64 {
+        if self.min_size_for.is_empty() {
+            return self.min_size;
+        }
+        let ext = self.extension(path);
+        self.min_size_for
+            .iter()
+            .find(|(wanted, _)| Some(wanted) == ext.as_ref())
+            .map_or(self.min_size, |&(_, min_size)| min_size)
+    }
+
+    // The extension of `path` as it is compared with the wanted ones
+    fn extension(&self, path: &Path) -> Option<String> {
+        let ext = path.extension()?.to_string_lossy();
+        if self.case_sensitive {
+            Some(ext.into_owned())
+        } else {
+            Some(ext.to_lowercase())
+        }
+    }
+}
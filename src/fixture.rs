@@ -0,0 +1,71 @@
+//! The tree of known duplicates that `dedup selftest` and the tests scan.
+//! Not part of the public API.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The block size that short hashes read, which the fixture's file sizes
+/// are chosen around.
+pub const BLOCK_LEN: usize = crate::HASH_BLOCK_LEN;
+
+/// A directory of its own for each test, removed when it is dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("dedup-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// `len` bytes that do not repeat within a block, different for each seed.
+pub fn pattern(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 31 + seed) % 251) as u8).collect()
+}
+
+fn with_last_byte_flipped(mut contents: Vec<u8>) -> Vec<u8> {
+    if let Some(last) = contents.last_mut() {
+        *last ^= 0xff;
+    }
+    contents
+}
+
+/// Creates three groups of duplicates below `root`: small files, files of
+/// several blocks and files of exactly one block. Next to them are empty
+/// files and files that only differ from another file in their last byte.
+pub fn create_fixture(root: &Path) -> io::Result<()> {
+    let big = pattern(3 * BLOCK_LEN + 123, 1);
+    let block = pattern(BLOCK_LEN, 2);
+    let over_block = pattern(BLOCK_LEN + 1, 3);
+    let files: Vec<(&str, Vec<u8>)> = vec![
+        ("small/a.txt", b"hello\n".to_vec()),
+        ("small/nested/b.txt", b"hello\n".to_vec()),
+        ("empty/a", Vec::new()),
+        ("empty/b", Vec::new()),
+        ("big/a", big.clone()),
+        ("big/b", big.clone()),
+        ("big/differs-at-end", with_last_byte_flipped(big)),
+        ("block/a", block.clone()),
+        ("block/b", block),
+        ("over-block/a", over_block.clone()),
+        (
+            "over-block/differs-at-end",
+            with_last_byte_flipped(over_block),
+        ),
+    ];
+    for (name, contents) in files {
+        let path = root.join(name);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, contents)?;
+    }
+    Ok(())
+}
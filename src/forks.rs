@@ -0,0 +1,19 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+// Opens the resource fork of the file at `path`. Returns None if it has none.
+#[cfg(target_os = "macos")]
+pub(crate) fn open_resource_fork(path: &Path) -> io::Result<Option<File>> {
+    match File::open(path.join("..namedfork/rsrc")) {
+        Ok(fork) => Ok(Some(fork)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// Other platforms have no forks that can be read through a path
+#[cfg(not(target_os = "macos"))]
+pub(crate) fn open_resource_fork(_path: &Path) -> io::Result<Option<File>> {
+    Ok(None)
+}
@@ -0,0 +1,96 @@
+use crate::Hash;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::Arc;
+
+/// A hash function that files are compared by. SHA-256 is used unless
+/// another one is given through `ScanOptions::hasher`.
+pub trait Hasher {
+    fn update(&mut self, bytes: &[u8]);
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+impl Hasher for Sha256 {
+    fn update(&mut self, bytes: &[u8]) {
+        Digest::update(self, bytes);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        Digest::finalize(*self).to_vec()
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        blake3::Hasher::update(self, bytes);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        blake3::Hasher::finalize(&self).as_bytes().to_vec()
+    }
+}
+
+/// Creates a fresh `Hasher` for every file. Outputs shorter than a `Hash`
+/// are padded with zeros, and longer ones truncated to its 32 bytes.
+#[derive(Clone)]
+pub struct HasherFactory(Arc<dyn Fn() -> Box<dyn Hasher> + Send + Sync>);
+
+impl HasherFactory {
+    pub fn new<F>(new_hasher: F) -> Self
+    where
+        F: Fn() -> Box<dyn Hasher> + Send + Sync + 'static,
+    {
+        HasherFactory(Arc::new(new_hasher))
+    }
+
+    pub(crate) fn hasher(&self) -> Box<dyn Hasher> {
+        (self.0)()
+    }
+
+    /// The built-in hash functions by name, the default one first. BLAKE3 is
+    /// considerably faster than SHA-256 on CPUs without SHA extensions.
+    pub fn builtin() -> Vec<(&'static str, HasherFactory)> {
+        vec![
+            ("sha256", HasherFactory::default()),
+            (
+                "blake3",
+                HasherFactory::new(|| Box::new(blake3::Hasher::new())),
+            ),
+        ]
+    }
+
+    /// The built-in hash function called `name`, if there is one.
+    pub fn by_name(name: &str) -> Option<HasherFactory> {
+        HasherFactory::builtin()
+            .into_iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, factory)| factory)
+    }
+
+    /// Hashes `bytes` in one go.
+    pub fn hash_bytes(&self, bytes: &[u8]) -> Hash {
+        let mut hasher = self.hasher();
+        hasher.update(bytes);
+        finalize(hasher)
+    }
+}
+
+impl Default for HasherFactory {
+    fn default() -> Self {
+        HasherFactory::new(|| Box::new(Sha256::new()))
+    }
+}
+
+impl fmt::Debug for HasherFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HasherFactory")
+    }
+}
+
+pub(crate) fn finalize(hasher: Box<dyn Hasher>) -> Hash {
+    let bytes = hasher.finalize();
+    let mut hash = Hash::default();
+    let len = bytes.len().min(hash.len());
+    hash[..len].copy_from_slice(&bytes[..len]);
+    hash
+}
@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOS: u8 = 0xda;
+// Segments holding metadata rather than image data: APP1 (EXIF and XMP),
+// APP13 (IPTC) and comments. Other APPn segments, such as the ICC profile in
+// APP2 or the color transform in APP14, change how the image looks.
+const METADATA_MARKERS: [u8; 3] = [0xe1, 0xed, 0xfe];
+
+pub(crate) fn is_jpeg(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        ["jpg", "jpeg", "jpe"]
+            .iter()
+            .any(|jpeg| ext.eq_ignore_ascii_case(jpeg))
+    })
+}
+
+// Reads the segments in front of the image data, leaving out metadata
+// segments. Returns those bytes and the offset of the start of scan marker,
+// from which the rest of the file is image data. Returns None if the file is
+// not a JPEG or is malformed, so that it is compared by its raw bytes.
+fn parse_header(file: &mut File) -> io::Result<Option<(Vec<u8>, u64)>> {
+    let mut reader = BufReader::new(&mut *file);
+    let mut marker = [0u8; 2];
+    if reader.read_exact(&mut marker).is_err() || marker != [0xff, SOI] {
+        return Ok(None);
+    }
+    let mut header = marker.to_vec();
+    let mut offset = 2u64;
+    loop {
+        if reader.read_exact(&mut marker).is_err() || marker[0] != 0xff {
+            return Ok(None);
+        }
+        match marker[1] {
+            SOS => return Ok(Some((header, offset))),
+            // Markers without a length
+            EOI | 0x01 | 0xd0..=0xd7 => {
+                header.extend_from_slice(&marker);
+                offset += 2;
+                if marker[1] == EOI {
+                    return Ok(Some((header, offset)));
+                }
+            }
+            _ => {
+                let mut len = [0u8; 2];
+                if reader.read_exact(&mut len).is_err() {
+                    return Ok(None);
+                }
+                // The length includes its own two bytes
+                let Some(body_len) = u16::from_be_bytes(len).checked_sub(2) else {
+                    return Ok(None);
+                };
+                if METADATA_MARKERS.contains(&marker[1]) {
+                    reader.seek_relative(body_len as i64)?;
+                } else {
+                    let start = header.len();
+                    header.extend_from_slice(&marker);
+                    header.extend_from_slice(&len);
+                    header.resize(start + 4 + body_len as usize, 0);
+                    if reader.read_exact(&mut header[start + 4..]).is_err() {
+                        return Ok(None);
+                    }
+                }
+                offset += 4 + body_len as u64;
+            }
+        }
+    }
+}
+
+// Returns the size of the file without its metadata segments, or None if it
+// is not a JPEG
+pub(crate) fn stripped_size(path: &Path) -> io::Result<Option<u64>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    Ok(parse_header(&mut file)?
+        .map(|(header, image_offset)| header.len() as u64 + len.saturating_sub(image_offset)))
+}
+
+// Opens the file for reading without its metadata segments. Files that are
+// not JPEGs are read as they are.
+pub(crate) fn open_stripped(path: &Path) -> io::Result<Box<dyn Read>> {
+    let mut file = File::open(path)?;
+    match parse_header(&mut file)? {
+        Some((header, image_offset)) => {
+            file.seek(SeekFrom::Start(image_offset))?;
+            Ok(Box::new(Cursor::new(header).chain(file)))
+        }
+        None => {
+            file.rewind()?;
+            Ok(Box::new(file))
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Report of a run, in the form printed by `--format json`.
+#[derive(Serialize)]
+pub struct Report {
+    pub files_processed: u64,
+    pub duplicates: u64,
+    pub saved_bytes: u64,
+    pub groups: Vec<Group>,
+    pub errors: Vec<Error>,
+}
+
+#[derive(Serialize)]
+pub struct Group {
+    pub size: u64,
+    pub hash: String,
+    pub paths: Vec<String>,
+    /// Paths that are not valid UTF-8, by their index in `paths`, as base64
+    /// of their bytes. Their entries in `paths` have U+FFFD in place of the
+    /// invalid bytes.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub raw_paths: BTreeMap<usize, String>,
+}
+
+#[derive(Serialize)]
+pub struct Error {
+    pub path: String,
+    /// Base64 of the bytes of `path` if it is not valid UTF-8.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_path: Option<String>,
+    pub kind: String,
+    pub message: String,
+}
+
+/// Changes planned before any is made, in the form written by `--plan-json`.
+#[derive(Serialize)]
+pub struct Plan {
+    pub version: &'static str,
+    /// The command line of the run, without the program name.
+    pub arguments: Vec<String>,
+    pub dry_run: bool,
+    pub actions: Vec<PlannedAction>,
+}
+
+#[derive(Serialize)]
+pub struct PlannedAction {
+    /// `remove`, `symlink` or, without either option, `none`.
+    pub operation: &'static str,
+    pub size: u64,
+    pub hash: String,
+    pub duplicate: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_duplicate: Option<String>,
+    pub keeper: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_keeper: Option<String>,
+    /// The target of the symlink that replaces the duplicate, relative to
+    /// its directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+}
+
+/// Duplicate groups collected over many runs by `--append-results`, by the
+/// full hash of their contents.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Catalog {
+    pub groups: BTreeMap<String, CatalogEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub size: u64,
+    /// Absolute paths of every copy seen, as in `Group::paths`.
+    pub paths: BTreeSet<String>,
+}
+
+pub fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// The bytes of `path` in base64, if it is not valid UTF-8, so that
+/// `path_string` loses information.
+pub fn raw_path(path: &Path) -> Option<String> {
+    match path.to_str() {
+        Some(_) => None,
+        None => Some(base64(path.as_os_str().as_bytes())),
+    }
+}
+
+// Standard base64 with padding, as decoded by `base64 -d`
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | ((byte as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+impl Plan {
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut out, self)?;
+        writeln!(out)?;
+        out.flush()
+    }
+}
+
+impl Report {
+    pub fn print(&self) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, self)?;
+        writeln!(stdout)
+    }
+}
@@ -0,0 +1,1337 @@
+use generic_array::GenericArray;
+use multimap::MultiMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::io::{BufRead, IsTerminal, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info_span, trace};
+use walkdir::{DirEntry, WalkDir};
+
+use filter::Filter;
+use hasher::HasherFactory;
+use manifest::{Checkpoint, PriorManifest};
+use normalize::Normalizer;
+use progress::Progress;
+
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "cdc")]
+pub mod cdc;
+#[cfg(feature = "decompress")]
+mod decompress;
+pub mod diff;
+pub mod dirs;
+mod eol;
+pub mod estimate;
+mod filter;
+#[doc(hidden)]
+pub mod fixture;
+mod forks;
+pub mod hasher;
+mod jpeg;
+pub mod manifest;
+pub mod normalize;
+mod progress;
+#[cfg(feature = "fuzzy")]
+pub mod similar;
+pub mod symlinks;
+
+const HASH_BLOCK_LEN: usize = 65536;
+const HASH_BUFLEN: usize = 65536;
+// Files up to this size are hashed in full right away when they are the
+// second of their size: reading the first block of both and then, if it
+// matches, all of both costs more than reading both once.
+const DIRECT_HASH_MAX_LEN: u64 = 4 * HASH_BLOCK_LEN as u64;
+const MIN_HASH_BUFLEN: usize = 16 * 1024;
+const MAX_HASH_BUFLEN: usize = 4 * 1024 * 1024;
+const TUNING_SAMPLES: u32 = 8;
+
+pub type Hash = GenericArray<u8, sha2::digest::consts::U32>;
+
+/// Settings controlling which files are considered while scanning.
+#[derive(Debug, Default, Clone)]
+pub struct ScanOptions {
+    pub min_size: u64,
+    /// Minimum sizes for files with these extensions (with or without the
+    /// leading dot), in place of `min_size`. Extensions are matched like
+    /// `extensions`.
+    pub min_size_for: Vec<(String, u64)>,
+    pub max_depth: Option<usize>,
+    pub one_file_system: bool,
+    /// Tune the read buffer size to the measured throughput.
+    pub adaptive_buffer: bool,
+    /// Only consider files with one of these extensions (with or without the
+    /// leading dot). All files are considered if this is empty.
+    pub extensions: Vec<String>,
+    /// Skip files and directories whose path matches one of these globs.
+    pub exclude: Vec<String>,
+    /// Never consider these files, such as the caller's own output files.
+    /// They are matched by location, so they need not exist yet and may be
+    /// given relative to the working directory.
+    pub skip_files: Vec<PathBuf>,
+    /// Only consider files duplicates if their extensions match as well.
+    pub same_ext: bool,
+    /// Match `extensions`, `exclude` and, with `same_ext`, the extensions of
+    /// duplicates case-sensitively.
+    pub case_sensitive: bool,
+    /// Leave this many bytes at the end of each file out of the comparison.
+    /// Files no larger than this are skipped.
+    pub ignore_trailing: u64,
+    /// What to do when a file cannot be read.
+    pub on_error: ErrorPolicy,
+    /// Compare symlinks to files by the contents of their targets, rather
+    /// than skipping them. Group members may then be symlinks.
+    pub resolve_input_symlinks: bool,
+    /// Stop scanning after this many candidate files.
+    pub max_files: Option<u64>,
+    /// Show the progress and the estimated time left on stderr. Takes an
+    /// extra pass over the directory tree to sum up the sizes of the files.
+    pub progress: bool,
+    /// Hashes from an earlier run, used instead of reading files again whose
+    /// size and modification time are unchanged. Not used unless it was
+    /// written with the same `ignore_trailing` and hasher; see
+    /// `PriorManifest::matches`.
+    pub since: Option<Arc<PriorManifest>>,
+    /// Walk the directories twice: first only counting file sizes, then
+    /// indexing just the files whose size is shared with another file. Keeps
+    /// paths of files with a unique size out of memory on huge trees.
+    pub two_pass: bool,
+    /// Write the full hashes computed so far to this file every minute and
+    /// at the end of the scan, in the `ManifestFormat::Plain` format. Lines
+    /// already in it are kept, so an interrupted scan can be resumed by
+    /// passing the file as `since`. Several processes may write the same
+    /// file: each write merges the new lines into it under an exclusive lock
+    /// on `<file>.lock` and replaces it by a rename, so readers never see a
+    /// partial file. Lines written with another `ignore_trailing` or hasher
+    /// are dropped. Ignored with `fast` or `include_forks`.
+    pub checkpoint: Option<PathBuf>,
+    /// Compare candidates by their full hash right away, rather than by the
+    /// hash of their first block first. Faster when most files of the same
+    /// size are duplicates. Files of up to 256 KiB are compared this way
+    /// regardless when a second file of their size is found.
+    pub no_short_hash: bool,
+    /// Hash function that files are compared by; SHA-256 by default.
+    pub hasher: HasherFactory,
+    /// Stop scanning as soon as this flag is set; checked between files.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Compare files by their first and last blocks only, never reading
+    /// them in full. Matches are not verified. Does not apply to files
+    /// compared by their decompressed contents.
+    pub fast: bool,
+    /// Compare the files this transform applies to by its output, such as
+    /// `Normalizer::strip_exif` or `Normalizer::normalize_eol`. Matches are
+    /// then not necessarily byte for byte identical.
+    pub normalize: Option<Normalizer>,
+    /// Include the resource fork of each file in its hash, so that files
+    /// whose main contents match but whose forks differ are not duplicates.
+    /// Only macOS has resource forks; elsewhere this has no effect.
+    pub include_forks: bool,
+    /// Compare gzip-compressed files by their decompressed contents.
+    #[cfg(feature = "decompress")]
+    pub decompress: bool,
+}
+
+/// What to do when a file cannot be read (or, in the CLI, changed).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorPolicy {
+    /// Report the error and carry on with the next file.
+    #[default]
+    Skip,
+    /// Stop and return the error.
+    Abort,
+    /// Ask whether to carry on; stops if standard input is not a terminal.
+    Prompt,
+}
+
+impl ErrorPolicy {
+    /// Handles `err`, which occurred for `path`, according to the policy.
+    /// Returns the error if processing should stop.
+    pub fn handle(self, path: &Path, err: io::Error) -> io::Result<()> {
+        match self {
+            ErrorPolicy::Skip => {
+                eprintln!("Skipping {:?}: {}", path, err);
+                Ok(())
+            }
+            ErrorPolicy::Abort => Err(err),
+            ErrorPolicy::Prompt => {
+                eprintln!("Error for {:?}: {}", path, err);
+                if confirm("Skip this file and continue?") {
+                    Ok(())
+                } else {
+                    Err(err)
+                }
+            }
+        }
+    }
+}
+
+/// Asks a yes/no question on the terminal. Returns false if standard input is
+/// not a terminal.
+pub fn confirm(question: &str) -> bool {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    stdin.read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
+/// A set of files with identical contents, in the order they were found.
+#[derive(Debug)]
+pub struct DuplicateGroup {
+    pub size: u64,
+    pub hash: Hash,
+    pub paths: Vec<PathBuf>,
+}
+
+/// Decision returned by the keeper callback for a `DuplicateGroup`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeeperChoice {
+    /// Keep the file at this index of `DuplicateGroup::paths`.
+    Keep(usize),
+    /// Leave every file in the group untouched.
+    Skip,
+}
+
+/// A planned action: `duplicate` is redundant given `keeper`.
+#[derive(Debug)]
+pub struct Action {
+    /// Index of the group in `Scan::groups`.
+    pub group: usize,
+    pub duplicate: PathBuf,
+    pub keeper: PathBuf,
+    pub size: u64,
+}
+
+/// A file or directory that was left out of the scan because of an error.
+#[derive(Debug)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub kind: io::ErrorKind,
+    pub message: String,
+}
+
+impl SkippedFile {
+    pub fn new(path: &Path, err: &io::Error) -> Self {
+        SkippedFile {
+            path: path.to_path_buf(),
+            kind: err.kind(),
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Result of `find_duplicates`.
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub num_files: u64,
+    /// Number of symlinks that were compared by the contents of their target.
+    pub resolved_symlinks: u64,
+    pub skipped_subtrees: u64,
+    /// Read buffer size used for full hashes at the end of the scan.
+    pub buffer_size: usize,
+    /// Number of files that were read a second time for a full hash.
+    pub full_hash_reads: u64,
+    /// Number of full hashes taken from `ScanOptions::since`.
+    pub reused_hashes: u64,
+    /// Number of bytes read to hash files, including short hashes.
+    pub bytes_read: u64,
+    /// Time taken by the scan, from the start of the walk until all groups
+    /// were found.
+    pub scan_time: Duration,
+    /// Rough estimate of the largest amount of memory taken by the index of
+    /// files during the scan.
+    pub peak_index_bytes: usize,
+    /// Whether the scan was stopped early through `ScanOptions::cancel`; the
+    /// groups found up to that point are still returned.
+    pub cancelled: bool,
+    /// Whether the scan stopped early because `ScanOptions::max_files` was
+    /// reached.
+    pub reached_max_files: bool,
+    /// Number of files considered, by size.
+    pub file_sizes: BTreeMap<u64, u64>,
+    pub groups: Vec<DuplicateGroup>,
+    pub actions: Vec<Action>,
+    /// Files and directories skipped because of errors.
+    pub errors: Vec<SkippedFile>,
+}
+
+#[derive(Debug)]
+enum SizeMapEntry {
+    // Boxed, as most sizes only ever have one file
+    One(Box<Path>),
+    Multiple(MultiMap<Hash, PathBuf>),
+}
+
+struct Index {
+    // Keyed by apparent size alone, so only regular files may enter: the
+    // length of a device or FIFO says nothing about what reading it yields.
+    // Sparse files are regular files and are compared by their contents,
+    // holes read as zeros.
+    size_map: BTreeMap<u64, SizeMapEntry>,
+    hashes: HashCache,
+    // Whether files only match if their extensions do, and if so, whether
+    // extensions are compared case-sensitively
+    same_ext: Option<bool>,
+    // Estimate of the memory taken by `size_map`
+    approx_bytes: usize,
+}
+
+impl Index {
+    fn new(hashes: HashCache, options: &ScanOptions) -> Self {
+        Index {
+            size_map: BTreeMap::new(),
+            hashes,
+            same_ext: options.same_ext.then_some(options.case_sensitive),
+            approx_bytes: 0,
+        }
+    }
+
+    // Estimate of the memory taken by the index, including cached hashes
+    fn memory_estimate(&self) -> usize {
+        self.approx_bytes + self.hashes.approx_bytes
+    }
+}
+
+// What a HashCache hashes of each file
+#[derive(Debug, Clone)]
+enum Contents {
+    Raw,
+    #[cfg_attr(not(feature = "decompress"), allow(dead_code))]
+    Decompressed,
+    Normalized(Normalizer),
+}
+
+// Computes short and full hashes of files, remembering full hashes by path.
+struct HashCache {
+    full_hashes: HashMap<PathBuf, Hash>,
+    tuner: BufferTuner,
+    contents: Contents,
+    fast: bool,
+    ignore_trailing: u64,
+    // Estimate of the memory taken by `full_hashes`
+    approx_bytes: usize,
+    // Number of full hashes computed by reading the file again
+    full_reads: u64,
+    // Number of bytes read for all hashes
+    bytes_read: u64,
+    factory: HasherFactory,
+    // Only used for raw contents, which is what the manifest covers
+    since: Option<Arc<PriorManifest>>,
+    // Number of full hashes taken from `since`
+    reused: u64,
+    // Use the full hash wherever the short hash would be used
+    no_short_hash: bool,
+    // Add the resource fork to the full hash; only for raw contents
+    include_forks: bool,
+    // Where full hashes read from files are saved; only set by
+    // `find_duplicates`, for the raw contents
+    checkpoint: Option<Checkpoint>,
+}
+
+// Picks the read buffer size for full hashes by hill climbing: the buffer is
+// doubled (or halved, once doubling stops helping) for as long as the
+// throughput measured on the next file improves.
+struct BufferTuner {
+    buf_len: usize,
+    best: Option<(usize, f64)>,
+    growing: bool,
+    reversed: bool,
+    samples: u32,
+    settled: bool,
+}
+
+impl BufferTuner {
+    fn new(adaptive: bool) -> Self {
+        BufferTuner {
+            buf_len: HASH_BUFLEN,
+            best: None,
+            growing: true,
+            reversed: false,
+            samples: 0,
+            settled: !adaptive,
+        }
+    }
+
+    fn record(&mut self, bytes: u64, elapsed: Duration) {
+        // Files that fit in the buffer say nothing about its size
+        if self.settled || bytes < self.buf_len as u64 {
+            return;
+        }
+        let throughput = bytes as f64 / elapsed.as_secs_f64().max(1e-9);
+        match self.best {
+            Some((best_len, best)) if throughput <= best => {
+                self.buf_len = best_len;
+                if self.reversed {
+                    self.settled = true;
+                    return;
+                }
+                self.reversed = true;
+                self.growing = !self.growing;
+            }
+            _ => self.best = Some((self.buf_len, throughput)),
+        }
+
+        self.samples += 1;
+        let next = if self.growing {
+            self.buf_len * 2
+        } else {
+            self.buf_len / 2
+        };
+        if self.samples >= TUNING_SAMPLES || !(MIN_HASH_BUFLEN..=MAX_HASH_BUFLEN).contains(&next) {
+            self.buf_len = self.best.map_or(self.buf_len, |(len, _)| len);
+            self.settled = true;
+        } else {
+            self.buf_len = next;
+        }
+    }
+}
+
+fn open_file(path: &Path, contents: &Contents) -> io::Result<Box<dyn Read>> {
+    if let Contents::Normalized(normalizer) = contents {
+        return normalizer.open(path);
+    }
+    let file = std::fs::File::open(path)?;
+    #[cfg(feature = "decompress")]
+    if matches!(contents, Contents::Decompressed) {
+        return Ok(Box::new(flate2::read::MultiGzDecoder::new(file)));
+    }
+    Ok(Box::new(file))
+}
+
+/// Hashes the first 64 KiB read from `reader`, or everything if it ends
+/// sooner. This is the cheap hash that candidates of the same size are first
+/// compared by; for inputs of at most 64 KiB it equals `hash_reader`.
+pub fn short_hash<R: Read>(file: R) -> io::Result<Hash> {
+    short_hash_with(file, &HasherFactory::default()).map(|(hash, _)| hash)
+}
+
+// Also returns the number of bytes read
+fn short_hash_with(mut file: impl Read, factory: &HasherFactory) -> io::Result<(Hash, u64)> {
+    let mut hasher = factory.hasher();
+    let mut buf = [0u8; HASH_BLOCK_LEN];
+    let mut total_read: usize = 0;
+
+    while total_read < HASH_BLOCK_LEN {
+        let read_bytes = file.read(&mut buf[total_read..])?;
+        if read_bytes == 0 {
+            break;
+        }
+        total_read += read_bytes;
+    }
+
+    hasher.update(&buf[..total_read]);
+    Ok((hasher::finalize(hasher), total_read as u64))
+}
+
+/// Hashes everything read from `reader`, e.g. an in-memory buffer, a network
+/// stream or decompressed data.
+pub fn hash_reader<R: Read>(reader: R) -> io::Result<Hash> {
+    compute_full_hash(reader, HASH_BUFLEN, &HasherFactory::default()).map(|(hash, _)| hash)
+}
+
+/// Hashes the contents of the file at `path`, as `hash_reader` would.
+pub fn hash_file(path: &Path) -> io::Result<Hash> {
+    hash_reader(std::fs::File::open(path)?)
+}
+
+// Feeds everything read from `file` to `hasher`; returns the number of bytes
+fn update_from(
+    hasher: &mut dyn hasher::Hasher,
+    mut file: impl Read,
+    buf_len: usize,
+) -> io::Result<u64> {
+    let mut buf = vec![0u8; buf_len];
+    let mut total_read: u64 = 0;
+
+    loop {
+        let read_bytes = file.read(&mut buf)?;
+        if read_bytes == 0 {
+            break;
+        }
+        hasher.update(&buf[..read_bytes]);
+        total_read += read_bytes as u64;
+    }
+    Ok(total_read)
+}
+
+// Returns the hash and the number of bytes read
+fn compute_full_hash(
+    file: impl Read,
+    buf_len: usize,
+    factory: &HasherFactory,
+) -> io::Result<(Hash, u64)> {
+    let mut hasher = factory.hasher();
+    let total_read = update_from(hasher.as_mut(), file, buf_len)?;
+    Ok((hasher::finalize(hasher), total_read))
+}
+
+impl HashCache {
+    fn new(options: &ScanOptions, contents: Contents) -> Self {
+        let raw = matches!(contents, Contents::Raw);
+        HashCache {
+            full_hashes: HashMap::new(),
+            tuner: BufferTuner::new(options.adaptive_buffer),
+            contents,
+            fast: options.fast && raw,
+            ignore_trailing: options.ignore_trailing,
+            approx_bytes: 0,
+            full_reads: 0,
+            bytes_read: 0,
+            factory: options.hasher.clone(),
+            since: options.since.clone().filter(|since| {
+                raw && !options.fast && !options.include_forks && since.matches(options)
+            }),
+            reused: 0,
+            no_short_hash: options.no_short_hash,
+            include_forks: options.include_forks && raw,
+            checkpoint: None,
+        }
+    }
+
+    // Opens the part of the file at `path` (of the given size) that is hashed
+    fn open(&self, path: &Path, size: u64) -> io::Result<impl Read> {
+        Ok(open_file(path, &self.contents)?.take(size.saturating_sub(self.ignore_trailing)))
+    }
+
+    fn short_hash(&mut self, path: &Path, size: u64) -> io::Result<Hash> {
+        if self.no_short_hash {
+            return self.full_hash(path, size);
+        }
+        trace!(?path, size, "short hash");
+        let (hash, read) = short_hash_with(self.open(path, size)?, &self.factory)?;
+        self.bytes_read += read;
+        // The short hash of a file that fits in one block covers all of it,
+        // so it is the full hash as well, unless that includes the fork
+        if size.saturating_sub(self.ignore_trailing) <= HASH_BLOCK_LEN as u64 && !self.include_forks
+        {
+            self.remember(path, hash);
+        }
+        Ok(hash)
+    }
+
+    fn full_hash(&mut self, path: &Path, size: u64) -> io::Result<Hash> {
+        if let Some(hash) = self.full_hashes.get(path) {
+            return Ok(*hash);
+        }
+        if let Some(hash) = self
+            .since
+            .as_ref()
+            .and_then(|since| since.hash_of(path, size))
+        {
+            trace!(?path, "full hash from the prior manifest");
+            self.reused += 1;
+            self.remember(path, hash);
+            return Ok(hash);
+        }
+        debug!(?path, size, fast = self.fast, "escalating to full hash");
+        let hash = if self.fast {
+            self.sample_hash(path, size)?
+        } else {
+            let metadata = match self.checkpoint {
+                Some(_) => Some(std::fs::metadata(path)?),
+                None => None,
+            };
+            let hash = self.hash_uncached(path, size)?;
+            if let (Some(checkpoint), Some(metadata)) = (&mut self.checkpoint, metadata) {
+                checkpoint.record(path, &metadata, &hash);
+            }
+            hash
+        };
+        self.full_reads += 1;
+        self.remember(path, hash);
+        Ok(hash)
+    }
+
+    fn remember(&mut self, path: &Path, hash: Hash) {
+        if self.full_hashes.insert(path.to_path_buf(), hash).is_none() {
+            self.approx_bytes += std::mem::size_of::<Hash>() + path_cost(path);
+        }
+    }
+
+    fn forget(&mut self, path: &Path) {
+        if self.full_hashes.remove(path).is_some() {
+            self.approx_bytes -= std::mem::size_of::<Hash>() + path_cost(path);
+        }
+    }
+
+    // Hashes the first and the last block of the file, or the whole file if
+    // it is no larger than two blocks
+    fn sample_hash(&mut self, path: &Path, size: u64) -> io::Result<Hash> {
+        let len = size.saturating_sub(self.ignore_trailing);
+        let block_len = HASH_BLOCK_LEN as u64;
+        if len <= 2 * block_len {
+            return self.hash_uncached(path, size);
+        }
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = self.factory.hasher();
+        let mut total_read =
+            update_from(hasher.as_mut(), (&mut file).take(block_len), HASH_BLOCK_LEN)?;
+        file.seek(SeekFrom::Start(len - block_len))?;
+        total_read += update_from(hasher.as_mut(), file.take(block_len), HASH_BLOCK_LEN)?;
+        total_read += self.update_from_fork(hasher.as_mut(), path)?;
+        self.bytes_read += total_read;
+        Ok(hasher::finalize(hasher))
+    }
+
+    // Computes the full hash without consulting or filling the cache
+    fn hash_uncached(&mut self, path: &Path, size: u64) -> io::Result<Hash> {
+        let start = Instant::now();
+        let mut hasher = self.factory.hasher();
+        let mut total_read =
+            update_from(hasher.as_mut(), self.open(path, size)?, self.tuner.buf_len)?;
+        total_read += self.update_from_fork(hasher.as_mut(), path)?;
+        self.tuner.record(total_read, start.elapsed());
+        self.bytes_read += total_read;
+        Ok(hasher::finalize(hasher))
+    }
+
+    // Feeds the resource fork of the file at `path` to `hasher`, after its
+    // main contents. As those have the same length for all candidates, the
+    // fork always starts at the same offset.
+    fn update_from_fork(&self, hasher: &mut dyn hasher::Hasher, path: &Path) -> io::Result<u64> {
+        if !self.include_forks {
+            return Ok(0);
+        }
+        match forks::open_resource_fork(path)? {
+            Some(fork) => update_from(hasher, fork, HASH_BUFLEN),
+            None => Ok(0),
+        }
+    }
+}
+
+// Rough number of heap and table bytes taken by one path in the index
+fn path_cost(path: &Path) -> usize {
+    std::mem::size_of::<PathBuf>() + path.as_os_str().len()
+}
+
+// Whether two files may be duplicates as far as their extensions are
+// concerned; see `Index::same_ext`. Files without an extension only match
+// each other.
+fn extensions_match(same_ext: Option<bool>, a: &Path, b: &Path) -> bool {
+    match same_ext {
+        None => true,
+        Some(true) => a.extension() == b.extension(),
+        Some(false) => {
+            let lowercase = |path: &Path| {
+                path.extension()
+                    .map(|ext| ext.to_string_lossy().to_lowercase())
+            };
+            lowercase(a) == lowercase(b)
+        }
+    }
+}
+
+fn check_index(path: &Path, size: u64, index: &mut Index) -> io::Result<Option<PathBuf>> {
+    use std::collections::btree_map::Entry;
+    let index_entry = index.size_map.entry(size);
+    let duplicate_of = match index_entry {
+        Entry::Occupied(mut o) => match o.get_mut() {
+            SizeMapEntry::One(prev_path) => {
+                let mut hash_map: MultiMap<Hash, PathBuf> = MultiMap::new();
+                // Files within one block are read in full by the short hash
+                // anyway. Once the first file has a full hash, it has a
+                // duplicate, and others of its size likely are too.
+                let direct = size > HASH_BLOCK_LEN as u64
+                    && size <= DIRECT_HASH_MAX_LEN
+                    && !index.hashes.full_hashes.contains_key(&**prev_path);
+                let first_hash = |hashes: &mut HashCache, path: &Path| {
+                    if direct {
+                        hashes.full_hash(path, size)
+                    } else {
+                        hashes.short_hash(path, size)
+                    }
+                };
+                let prev_hash = first_hash(&mut index.hashes, prev_path);
+                if prev_hash.as_ref().is_err_and(is_vanished) {
+                    // Deleted since it was indexed: this file takes its place
+                    debug!(path = ?prev_path, "indexed file disappeared");
+                    index.hashes.forget(prev_path);
+                    *prev_path = path.into();
+                    return Ok(None);
+                }
+                let prev_hash = prev_hash?;
+                let new_hash = first_hash(&mut index.hashes, path)?;
+                if new_hash == prev_hash
+                    && extensions_match(index.same_ext, prev_path, path)
+                    && (direct
+                        || index.hashes.full_hash(prev_path, size)?
+                            == index.hashes.full_hash(path, size)?)
+                {
+                    Some(prev_path.to_path_buf())
+                } else {
+                    // Files of this size that come later are looked up by
+                    // their short hash
+                    let (prev_hash, new_hash) = if direct {
+                        (
+                            index.hashes.short_hash(prev_path, size)?,
+                            index.hashes.short_hash(path, size)?,
+                        )
+                    } else {
+                        (prev_hash, new_hash)
+                    };
+                    index.approx_bytes += 2 * (std::mem::size_of::<Hash>() + path_cost(path));
+                    hash_map.insert(prev_hash, prev_path.to_path_buf());
+                    hash_map.insert(new_hash, path.to_path_buf());
+                    *o.get_mut() = SizeMapEntry::Multiple(hash_map);
+                    None
+                }
+            }
+            SizeMapEntry::Multiple(hash_map) => {
+                let new_hash = index.hashes.short_hash(path, size)?;
+                let mut found = None;
+                if let Some(candidates) = hash_map.get_vec(&new_hash) {
+                    for prev_path in candidates {
+                        if !extensions_match(index.same_ext, prev_path, path) {
+                            continue;
+                        }
+                        let prev_hash = match index.hashes.full_hash(prev_path, size) {
+                            Err(err) if is_vanished(&err) => {
+                                debug!(path = ?prev_path, "indexed file disappeared");
+                                continue;
+                            }
+                            prev_hash => prev_hash?,
+                        };
+                        if prev_hash == index.hashes.full_hash(path, size)? {
+                            found = Some(prev_path.clone());
+                            break;
+                        }
+                    }
+                }
+                if found.is_none() {
+                    index.approx_bytes += std::mem::size_of::<Hash>() + path_cost(path);
+                    hash_map.insert(new_hash, path.to_path_buf());
+                }
+                found
+            }
+        },
+        Entry::Vacant(v) => {
+            index.approx_bytes += std::mem::size_of::<(u64, SizeMapEntry)>() + path_cost(path);
+            v.insert(SizeMapEntry::One(path.into()));
+            None
+        }
+    };
+
+    if let Some(prev_path) = &duplicate_of {
+        debug!(?path, duplicate_of = ?prev_path, "found duplicate");
+    }
+    // Duplicates are not added to the index, so their full hash is not
+    // needed again
+    if duplicate_of
+        .as_deref()
+        .is_some_and(|prev_path| prev_path != path)
+    {
+        index.hashes.forget(path);
+    }
+    Ok(duplicate_of)
+}
+
+/// Formats a hash as lowercase hexadecimal.
+pub fn to_hex(hash: &Hash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses a hash written as lowercase or uppercase hexadecimal, as by
+/// `to_hex`.
+pub fn from_hex(hex: &str) -> Option<Hash> {
+    if hex.len() != 2 * Hash::default().len() || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = Hash::default();
+    for (byte, digits) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Returns whether `err` was caused by a path exceeding the platform's
+/// length limit (`ENAMETOOLONG`).
+pub fn is_name_too_long(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::InvalidFilename
+}
+
+/// Returns whether `err` means that the file no longer exists (`ENOENT`),
+/// which is common on live trees where files are deleted while scanning.
+pub fn is_vanished(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::NotFound
+}
+
+/// Checks that the file at `path` can still be opened and read, and that it
+/// still has the given size.
+pub fn check_readable(path: &Path, size: u64) -> io::Result<()> {
+    let mut file = std::fs::File::open(path)?;
+    if file.metadata()?.len() != size {
+        return Err(io::Error::other("file size changed since it was scanned"));
+    }
+    if size > 0 {
+        file.read_exact(&mut [0u8; 1])?;
+    }
+    Ok(())
+}
+
+/// Compares the first `len` bytes of two files byte by byte, without relying
+/// on hashes.
+pub fn contents_equal(a: &Path, b: &Path, len: u64) -> io::Result<bool> {
+    let open = |path| {
+        std::fs::File::open(path)
+            .map(|file| io::BufReader::with_capacity(HASH_BUFLEN, file.take(len)))
+    };
+    let (mut a, mut b) = (open(a)?, open(b)?);
+    loop {
+        let (buf_a, buf_b) = (a.fill_buf()?, b.fill_buf()?);
+        let n = buf_a.len().min(buf_b.len());
+        if n == 0 {
+            return Ok(buf_a.is_empty() && buf_b.is_empty());
+        }
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+        a.consume(n);
+        b.consume(n);
+    }
+}
+
+/// Computes the path of `target` relative to the directory containing `base`,
+/// suitable as the target of a symlink at `base`. `base` itself may be a
+/// symlink; `target` is resolved.
+pub fn relative_path(base: &Path, target: &Path) -> io::Result<PathBuf> {
+    let name = base
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let parent = match base.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let abs_base = parent.canonicalize()?.join(name);
+    let abs_target = target.canonicalize()?;
+
+    let mut iter_base = abs_base.components();
+    let mut iter_target = abs_target.components().peekable();
+
+    loop {
+        let c_base = iter_base.next();
+        let c_target = iter_target.peek();
+        if c_base.is_none() || c_target.is_none() || c_base.unwrap() != *c_target.unwrap() {
+            break;
+        }
+        iter_target.next();
+    }
+
+    let relative = iter_base
+        .map(|a| match a {
+            std::path::Component::Normal(_) => std::path::Component::ParentDir,
+            _ => panic!(),
+        })
+        .chain(iter_target)
+        .collect::<PathBuf>();
+    Ok(relative)
+}
+
+struct Scanner {
+    index: Index,
+    // Index of compressed files by their decompressed contents
+    compressed_index: Index,
+    // Index of files that `ScanOptions::normalize` applies to, by its output
+    normalized_index: Index,
+    groups: Vec<DuplicateGroup>,
+    group_of: HashMap<PathBuf, usize>,
+    num_files: u64,
+    resolved_symlinks: u64,
+    file_sizes: BTreeMap<u64, u64>,
+    peak_index_bytes: usize,
+    progress: Option<Progress>,
+    // With `ScanOptions::two_pass`, the sizes shared by more than one file
+    colliding_sizes: Option<HashSet<u64>>,
+}
+
+impl Scanner {
+    fn handle_entry(
+        &mut self,
+        entry: &DirEntry,
+        options: &ScanOptions,
+        filter: &Filter,
+    ) -> io::Result<()> {
+        let is_link = entry.file_type().is_symlink() && options.resolve_input_symlinks;
+        if !(entry.file_type().is_file() || is_link) || !filter.has_wanted_extension(entry.path()) {
+            return Ok(());
+        }
+        let metadata = if is_link {
+            std::fs::metadata(entry.path())?
+        } else {
+            entry.metadata()?
+        };
+        // Symlinks to anything other than a file are left alone, and nothing
+        // but a regular file may enter an index; see `Index::size_map`
+        if !metadata.is_file() {
+            return Ok(());
+        }
+        if is_link {
+            self.resolved_symlinks += 1;
+        }
+        let size = metadata.len();
+        if let Some(progress) = &mut self.progress {
+            progress.advance(size);
+        }
+        // The size filter applies to the size that is compared, so that all
+        // members of a group are on the same side of the threshold
+        let decompressed = decompressed_size(entry.path(), options)?;
+        let normalized = match &options.normalize {
+            Some(normalizer) if decompressed.is_none() => normalizer.size(entry.path())?,
+            _ => None,
+        };
+        let (index, size, unique) = match (decompressed, normalized) {
+            (Some(decompressed_size), _) => (&mut self.compressed_index, decompressed_size, false),
+            (None, Some(normalized_size)) => (&mut self.normalized_index, normalized_size, false),
+            (None, None) => {
+                let unique = self
+                    .colliding_sizes
+                    .as_ref()
+                    .is_some_and(|sizes| !sizes.contains(&size));
+                (&mut self.index, size, unique)
+            }
+        };
+        if size > filter.min_size(entry.path()) && size > options.ignore_trailing {
+            // A file with a size of its own cannot have duplicates
+            if !unique {
+                trace!(path = ?entry.path(), size, "indexing file");
+                if let Some(prev_path) = check_index(entry.path(), size, index)? {
+                    if prev_path != entry.path() {
+                        let hash = index.hashes.full_hashes[&prev_path];
+                        self.add_duplicate(prev_path, entry.path().to_path_buf(), size, hash);
+                    }
+                }
+            }
+            self.num_files += 1;
+            *self.file_sizes.entry(size).or_default() += 1;
+            self.peak_index_bytes = self.peak_index_bytes.max(
+                self.index.memory_estimate()
+                    + self.compressed_index.memory_estimate()
+                    + self.normalized_index.memory_estimate(),
+            );
+        }
+        Ok(())
+    }
+
+    fn add_duplicate(&mut self, prev_path: PathBuf, path: PathBuf, size: u64, hash: Hash) {
+        match self.group_of.get(&prev_path) {
+            Some(&i) => self.groups[i].paths.push(path),
+            None => {
+                self.group_of.insert(prev_path.clone(), self.groups.len());
+                self.groups.push(DuplicateGroup {
+                    size,
+                    hash,
+                    paths: vec![prev_path, path],
+                });
+            }
+        }
+    }
+}
+
+// Returns the size of the decompressed contents if `path` is to be compared
+// by those rather than by its raw bytes.
+#[cfg(feature = "decompress")]
+fn decompressed_size(path: &Path, options: &ScanOptions) -> io::Result<Option<u64>> {
+    if options.decompress && decompress::is_compressed(path) {
+        decompress::decompressed_size(path)
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(not(feature = "decompress"))]
+fn decompressed_size(_path: &Path, _options: &ScanOptions) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+fn is_cancelled(options: &ScanOptions) -> bool {
+    options
+        .cancel
+        .as_ref()
+        .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+}
+
+// Walks the tree below `dir`, leaving out excluded paths and, with
+// `one_file_system`, subtrees on other devices (counted in `skipped_subtrees`)
+fn walk_root<'a>(
+    dir: &Path,
+    options: &ScanOptions,
+    filter: &'a Filter,
+    skipped_subtrees: &'a mut u64,
+) -> io::Result<impl Iterator<Item = walkdir::Result<DirEntry>> + 'a> {
+    // Sorted, so that the first file seen of each group, which is kept by
+    // default, does not depend on the order the file system lists entries in
+    let mut walk = WalkDir::new(dir).sort_by_file_name();
+    if let Some(max_depth) = options.max_depth {
+        walk = walk.max_depth(max_depth);
+    }
+    let root_dev = if options.one_file_system {
+        Some(std::fs::metadata(dir)?.dev())
+    } else {
+        None
+    };
+    Ok(walk.into_iter().filter_entry(move |entry| {
+        if filter.is_excluded(entry.path()) {
+            return false;
+        }
+        match root_dev {
+            Some(dev) if entry.metadata().is_ok_and(|m| m.dev() != dev) => {
+                *skipped_subtrees += 1;
+                false
+            }
+            _ => true,
+        }
+    }))
+}
+
+// Leaves out roots that are inside another root, or the same directory
+// spelled differently, so that no file is visited twice. Without that, a file
+// would be found to be a duplicate of itself.
+fn distinct_roots(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let resolved: Vec<PathBuf> = paths
+        .iter()
+        .map(|path| path.canonicalize().unwrap_or_else(|_| path.clone()))
+        .collect();
+    let mut roots = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        // Of two equal roots, the first one is kept
+        let covered = resolved.iter().enumerate().any(|(j, other)| {
+            j != i && resolved[i].starts_with(other) && (resolved[i] != *other || j < i)
+        });
+        if covered {
+            debug!(?path, "skipping root inside another root");
+        } else {
+            roots.push(path.clone());
+        }
+    }
+    roots
+}
+
+// Calls `f` with the path and size of every candidate file, reading only
+// metadata
+fn for_each_candidate(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+    filter: &Filter,
+    mut f: impl FnMut(DirEntry, u64),
+) -> io::Result<()> {
+    for dir in paths {
+        for entry in walk_root(dir, options, filter, &mut 0)?.flatten() {
+            let is_link = entry.file_type().is_symlink() && options.resolve_input_symlinks;
+            if !(entry.file_type().is_file() || is_link)
+                || !filter.has_wanted_extension(entry.path())
+            {
+                continue;
+            }
+            let metadata = if is_link {
+                std::fs::metadata(entry.path()).ok().filter(|m| m.is_file())
+            } else {
+                entry.metadata().ok()
+            };
+            if let Some(metadata) = metadata {
+                if metadata.len() > filter.min_size(entry.path()) {
+                    f(entry, metadata.len());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Counts the candidate files per size, without hashing anything
+fn count_sizes(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+    filter: &Filter,
+) -> io::Result<HashMap<u64, u64>> {
+    let mut sizes: HashMap<u64, u64> = HashMap::new();
+    for_each_candidate(paths, options, filter, |_, size| {
+        *sizes.entry(size).or_default() += 1
+    })?;
+    Ok(sizes)
+}
+
+/// Files of the same size, which may or may not have the same contents.
+#[derive(Debug)]
+pub struct SizeGroup {
+    pub size: u64,
+    pub paths: Vec<PathBuf>,
+}
+
+/// Walks `paths` and groups the files that share their size with another
+/// file, without reading any of them: the first step of `find_duplicates`.
+/// Groups are ordered by size, largest first, and the paths within a group in
+/// the order they were found.
+pub fn find_same_size_files(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+) -> io::Result<Vec<SizeGroup>> {
+    let paths = &distinct_roots(paths);
+    let filter = Filter::new(options)?;
+    let mut sizes: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    for_each_candidate(paths, options, &filter, |entry, size| {
+        sizes.entry(size).or_default().push(entry.into_path())
+    })?;
+    Ok(sizes
+        .into_iter()
+        .rev()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(size, paths)| SizeGroup { size, paths })
+        .collect())
+}
+
+/// An uncompressed file whose contents equal the decompressed contents of a
+/// compressed file.
+#[cfg(feature = "decompress")]
+#[derive(Debug)]
+pub struct CompressedCopy {
+    pub compressed: PathBuf,
+    pub plain: PathBuf,
+    /// Size of the uncompressed contents.
+    pub size: u64,
+    pub hash: Hash,
+}
+
+/// Walks `paths` and finds gzip-compressed files whose decompressed contents
+/// equal an uncompressed file, such as `notes.txt` and `notes.txt.gz`. A
+/// compressed file is only decompressed if there is an uncompressed file of
+/// its decompressed size, and only files of such a size are read.
+/// `ignore_trailing`, `fast` and `include_forks` do not apply.
+#[cfg(feature = "decompress")]
+pub fn find_compressed_copies(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+) -> io::Result<Vec<CompressedCopy>> {
+    let paths = &distinct_roots(paths);
+    let filter = Filter::new(options)?;
+    let mut compressed = Vec::new();
+    // By size modulo 4 GiB, which is all that a gzip trailer records
+    let mut plain: HashMap<u32, Vec<(PathBuf, u64)>> = HashMap::new();
+    for_each_candidate(paths, options, &filter, |entry, size| {
+        if decompress::is_compressed(entry.path()) {
+            compressed.push(entry.into_path());
+        } else {
+            plain
+                .entry(size as u32)
+                .or_default()
+                .push((entry.into_path(), size));
+        }
+    })?;
+
+    // Plain files are hashed whole, like the decompressed contents
+    let plain_options = ScanOptions {
+        ignore_trailing: 0,
+        fast: false,
+        include_forks: false,
+        ..options.clone()
+    };
+    let mut hashes = HashCache::new(&plain_options, Contents::Raw);
+    let mut copies = Vec::new();
+    for path in compressed {
+        let candidates = match decompress::trailer_size(&path) {
+            Ok(Some(size)) => match plain.get(&size) {
+                Some(candidates) => candidates,
+                None => continue,
+            },
+            Ok(None) => continue,
+            Err(err) => {
+                options.on_error.handle(&path, err)?;
+                continue;
+            }
+        };
+        let decompressed = std::fs::File::open(&path).and_then(|file| {
+            let reader = flate2::read::MultiGzDecoder::new(file);
+            compute_full_hash(reader, HASH_BUFLEN, &options.hasher)
+        });
+        let (hash, size) = match decompressed {
+            Ok(decompressed) => decompressed,
+            Err(err) => {
+                options.on_error.handle(&path, err)?;
+                continue;
+            }
+        };
+        for (candidate, _) in candidates.iter().filter(|(_, len)| *len == size) {
+            match hashes.full_hash(candidate, size) {
+                Ok(candidate_hash) if candidate_hash == hash => copies.push(CompressedCopy {
+                    compressed: path.clone(),
+                    plain: candidate.clone(),
+                    size,
+                    hash,
+                }),
+                Ok(_) => {}
+                Err(err) => options.on_error.handle(candidate, err)?,
+            }
+        }
+    }
+    Ok(copies)
+}
+
+/// Walks `paths` and collects groups of files with identical contents.
+///
+/// `choose_keeper` is called once per group to decide which file survives;
+/// every other member of the group becomes an `Action` in the returned `Scan`.
+pub fn find_duplicates<F>(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+    mut choose_keeper: F,
+) -> io::Result<Scan>
+where
+    F: FnMut(&DuplicateGroup) -> KeeperChoice,
+{
+    let _span = info_span!("scan", roots = paths.len()).entered();
+    let start = Instant::now();
+    let paths = &distinct_roots(paths);
+    let filter = Filter::new(options)?;
+    // Without a transform, the normalized index stays empty
+    let normalizer = options
+        .normalize
+        .clone()
+        .unwrap_or_else(|| Normalizer::new(|_| Ok(None)));
+    let mut scanner = Scanner {
+        index: Index::new(HashCache::new(options, Contents::Raw), options),
+        compressed_index: Index::new(HashCache::new(options, Contents::Decompressed), options),
+        normalized_index: Index::new(
+            HashCache::new(options, Contents::Normalized(normalizer)),
+            options,
+        ),
+        groups: Vec::new(),
+        group_of: HashMap::new(),
+        num_files: 0,
+        resolved_symlinks: 0,
+        file_sizes: BTreeMap::new(),
+        peak_index_bytes: 0,
+        progress: None,
+        colliding_sizes: None,
+    };
+    // Only hashes of the raw contents can be taken from a manifest
+    if !options.fast && !options.include_forks {
+        scanner.index.hashes.checkpoint = options
+            .checkpoint
+            .clone()
+            .map(|path| Checkpoint::new(path, options));
+    }
+    if options.progress || options.two_pass {
+        let sizes = count_sizes(paths, options, &filter)?;
+        if options.two_pass {
+            let colliding = sizes.iter().filter(|(_, &count)| count > 1);
+            scanner.colliding_sizes = Some(colliding.map(|(&size, _)| size).collect());
+        }
+        if options.progress {
+            scanner.progress = Some(Progress::new(sizes));
+        }
+    }
+    let mut skipped_subtrees = 0;
+    let mut cancelled = false;
+    let mut reached_max_files = false;
+    let mut errors = Vec::new();
+
+    'roots: for dir in paths {
+        let walk = walk_root(dir, options, &filter, &mut skipped_subtrees)?;
+        for _entry in walk {
+            if let Some(checkpoint) = &mut scanner.index.hashes.checkpoint {
+                checkpoint.write_if_due()?;
+            }
+            if is_cancelled(options) {
+                cancelled = true;
+                break 'roots;
+            }
+            if options
+                .max_files
+                .is_some_and(|max_files| scanner.num_files >= max_files)
+            {
+                reached_max_files = true;
+                break 'roots;
+            }
+            match &_entry {
+                Ok(entry) => match scanner.handle_entry(entry, options, &filter) {
+                    Ok(()) => {}
+                    // Not an error: the file was deleted after it was listed
+                    Err(err) if is_vanished(&err) => {
+                        debug!(path = ?entry.path(), "file disappeared, skipping");
+                    }
+                    Err(err) if is_name_too_long(&err) => {
+                        eprintln!("Skipping {:?}: {}", entry.path(), err);
+                        errors.push(SkippedFile::new(entry.path(), &err));
+                    }
+                    Err(err) => {
+                        let skipped = SkippedFile::new(entry.path(), &err);
+                        options.on_error.handle(entry.path(), err)?;
+                        errors.push(skipped);
+                    }
+                },
+                Err(err) => {
+                    eprintln!("{}", err);
+                    errors.push(SkippedFile {
+                        path: err.path().map(Path::to_path_buf).unwrap_or_default(),
+                        kind: err.io_error().map_or(io::ErrorKind::Other, io::Error::kind),
+                        message: err.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some(progress) = &scanner.progress {
+        progress.finish();
+    }
+    if let Some(checkpoint) = &mut scanner.index.hashes.checkpoint {
+        checkpoint.write()?;
+    }
+
+    let mut actions = Vec::new();
+    for (group_index, group) in scanner.groups.iter().enumerate() {
+        if let KeeperChoice::Keep(keeper) = choose_keeper(group) {
+            for (i, path) in group.paths.iter().enumerate() {
+                if i != keeper {
+                    actions.push(Action {
+                        group: group_index,
+                        duplicate: path.clone(),
+                        keeper: group.paths[keeper].clone(),
+                        size: group.size,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(Scan {
+        num_files: scanner.num_files,
+        resolved_symlinks: scanner.resolved_symlinks,
+        skipped_subtrees,
+        buffer_size: scanner.index.hashes.tuner.buf_len,
+        full_hash_reads: scanner.index.hashes.full_reads
+            + scanner.compressed_index.hashes.full_reads
+            + scanner.normalized_index.hashes.full_reads,
+        reused_hashes: scanner.index.hashes.reused,
+        bytes_read: scanner.index.hashes.bytes_read
+            + scanner.compressed_index.hashes.bytes_read
+            + scanner.normalized_index.hashes.bytes_read,
+        scan_time: start.elapsed(),
+        peak_index_bytes: scanner.peak_index_bytes,
+        cancelled,
+        reached_max_files,
+        file_sizes: scanner.file_sizes,
+        groups: scanner.groups,
+        actions,
+        errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_too_long_is_recognized() {
+        // Longer than NAME_MAX, which is 255 bytes on common file systems
+        let name = "x".repeat(300);
+        let err = std::fs::metadata(std::env::temp_dir().join(name)).unwrap_err();
+        assert!(is_name_too_long(&err));
+        let err = std::fs::metadata(std::env::temp_dir().join("dedup-does-not-exist")).unwrap_err();
+        assert!(!is_name_too_long(&err));
+    }
+}
@@ -1,23 +1,61 @@
-use clap::Parser;
-use generic_array::GenericArray;
-use multimap::MultiMap;
+use anyhow::Context;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use config::Config;
+use dedup::diff::diff_trees;
+use dedup::dirs::find_duplicate_dirs;
+use dedup::estimate::estimate_savings;
+use dedup::hasher::HasherFactory;
+#[cfg(feature = "remote")]
+use dedup::manifest::find_known_files;
+use dedup::manifest::{write_hash_manifest, ManifestFormat, PriorManifest};
+use dedup::normalize::Normalizer;
+use dedup::symlinks::find_duplicate_symlinks;
+use dedup::{
+    check_readable, confirm, contents_equal, find_duplicates, find_same_size_files, from_hex,
+    hash_file, is_name_too_long, relative_path, short_hash, to_hex, Action, DuplicateGroup,
+    ErrorPolicy, Hash, KeeperChoice, Scan, ScanOptions, SkippedFile,
+};
+use metrics::Metrics;
 use number_prefix::NumberPrefix;
-use sha2::{Digest, Sha256};
-use std::collections::{BTreeMap, HashMap};
-use std::io::Read;
+use rayon::prelude::*;
+use regex::Regex;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{fs, io};
-use walkdir::{DirEntry, WalkDir};
+use tracing_subscriber::EnvFilter;
 
-const HASH_BLOCK_LEN: usize = 65536;
-const HASH_BUFLEN: usize = 65536;
+mod config;
+#[cfg(feature = "sqlite")]
+mod db;
+mod json;
+mod metrics;
+#[cfg(feature = "remote")]
+mod remote;
+mod selftest;
+#[cfg(test)]
+mod tests;
+mod xml;
 
 #[derive(Parser)]
 #[command(
     name = "dedup",
-    about = "Find duplicate files in a directory structure"
+    about = "Find duplicate files in a directory structure",
+    after_help = "Defaults for options can be set in a TOML config file. Options given on the command line take precedence over the config file.",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
-struct Options {
+pub struct Options {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(
         short,
         long,
@@ -26,6 +64,15 @@ struct Options {
     )]
     min_size: u64,
 
+    #[arg(
+        long,
+        value_name = "EXT=SIZE",
+        value_delimiter = ',',
+        value_parser = parse_min_size_for,
+        help = "Minimum size of files with this extension, in place of --min-size, e.g. '.jpg=1M,.txt=100K' (comma-separated, can be repeated)"
+    )]
+    min_size_for: Vec<(String, u64)>,
+
     #[arg(
         short,
         long,
@@ -33,259 +80,2960 @@ struct Options {
     )]
     verbose: bool,
 
-    #[arg(
-        long,
-        short = 'd',
-        help = "Do not search files beyond this depth. Files in the specified paths are considered depth 1."
-    )]
-    max_depth: Option<usize>,
+    #[arg(
+        long,
+        short = 'd',
+        help = "Do not search files beyond this depth. Files in the specified paths are considered depth 1."
+    )]
+    max_depth: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 0,
+        help = "Ignore this many bytes at the end of each file when comparing, e.g. a metadata footer"
+    )]
+    ignore_trailing: u64,
+
+    #[arg(
+        long = "ext",
+        value_name = "EXT",
+        value_delimiter = ',',
+        help = "Only search files with these extensions (comma-separated, can be repeated)"
+    )]
+    extensions: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Skip files and directories whose path matches this glob (can be repeated)"
+    )]
+    exclude: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Match --ext, --exclude and --same-ext case-sensitively (they ignore case by default)"
+    )]
+    case_sensitive: bool,
+
+    #[arg(
+        long,
+        help = "Only treat files as duplicates if they also have the same extension"
+    )]
+    same_ext: bool,
+
+    #[arg(
+        long,
+        help = "Compare symlinks to files by the contents of their targets. Symlinks are never kept as the original"
+    )]
+    resolve_input_symlinks: bool,
+
+    #[arg(long, help = "Stop searching after this many files")]
+    max_files: Option<u64>,
+
+    #[arg(
+        short = 'x',
+        long,
+        help = "Do not descend into directories on other file systems"
+    )]
+    one_file_system: bool,
+
+    #[arg(
+        short = 's',
+        long = "symlink",
+        group = "mode",
+        help = "Replace duplicate files by symlinks"
+    )]
+    replace_by_symlink: bool,
+
+    #[arg(long, group = "mode", help = "Remove duplicate files")]
+    remove: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "library",
+        help = "With --symlink or --remove, print what would be done, including the exact symlink targets, without changing anything"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "library",
+        help = "With --symlink or --remove, write the commands that would be run to this shell script instead of changing anything"
+    )]
+    emit_script: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "library",
+        help = "Write the planned changes to this JSON file before making any, with the version and command line, as a record of the run"
+    )]
+    plan_json: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Run this shell command for each duplicate, after --symlink or --remove if given; {dup}, {keeper}, {size} and {hash} are replaced by the quoted duplicate path, the kept path, the size and the hash"
+    )]
+    exec: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["remove", "dirs"],
+        help = "Move one copy of each group into this directory, unless one is already there, and replace all others by symlinks to it"
+    )]
+    library: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Handle at most this many duplicates in this run, then stop"
+    )]
+    max_actions: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        help = "Handle the largest duplicates first and stop changing files once this much space is freed, only reporting the rest, e.g. 50G"
+    )]
+    free_target: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "K",
+        default_value_t = 2,
+        value_parser = clap::value_parser!(u64).range(2..),
+        help = "Only remove or link groups with at least this many copies, counting the one kept; smaller groups are only reported"
+    )]
+    min_dupes_to_act: u64,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        help = "Ask for confirmation before changing files if more than this much data would be replaced, e.g. 10G"
+    )]
+    confirm_over: Option<u64>,
+
+    #[arg(
+        short = 'y',
+        long,
+        help = "Do not ask any questions and assume yes: skip --confirm-over and skip unreadable files with --on-error prompt"
+    )]
+    yes: bool,
+
+    #[arg(
+        long,
+        help = "With --remove, also remove directories left empty by removing duplicates"
+    )]
+    prune_empty_dirs: bool,
+
+    #[arg(
+        long,
+        help = "Leave duplicates alone that have other hard links, as removing them frees no space"
+    )]
+    skip_multilinked: bool,
+
+    #[arg(
+        long,
+        help = "With --symlink, check that each new link would lead to the kept file before creating it, and leave the duplicate alone if not"
+    )]
+    link_check: bool,
+
+    #[arg(
+        long,
+        help = "Compare all files of a group byte by byte before changing any of them, and skip the group if they differ"
+    )]
+    verify_all: bool,
+
+    #[arg(
+        long,
+        requires = "verify_all",
+        help = "Verify groups for --verify-all in parallel, which is faster on SSDs and RAID"
+    )]
+    parallel_verify: bool,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Only change files below this directory (can be repeated); all other files are left alone"
+    )]
+    writable: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with = "library",
+        help = "Only report, remove or link duplicates below this directory, keeping a copy outside it where there is one; the whole tree is still searched"
+    )]
+    act_under: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Set the access time of each kept file to now once its duplicates were replaced"
+    )]
+    touch_keeper: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Never change files whose hash is listed in this file, one hex hash of --algorithm per line (a --hash-manifest or sha256sum file also works)"
+    )]
+    keep_hashes: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = KeepStrategy::First,
+        help = "Which file of each group of duplicates to keep"
+    )]
+    keep: KeepStrategy,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        value_parser = Regex::new,
+        help = "Keep the first file of each group whose path matches this regular expression, falling back to --keep"
+    )]
+    keep_matching: Option<Regex>,
+
+    #[arg(
+        long,
+        help = "Use the inode change time (ctime) instead of the modification time for --keep oldest/newest"
+    )]
+    keep_by_ctime: bool,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 0.0,
+        help = "For --keep oldest/newest, treat timestamps this close to the oldest or newest one as equal and keep the first of those files found, e.g. 2 for FAT"
+    )]
+    mtime_tolerance: f64,
+
+    #[arg(
+        long,
+        help = "Always keep a copy under the earliest listed directory that has one, choosing among those copies as usual"
+    )]
+    first_root_wins: bool,
+
+    #[arg(
+        long,
+        help = "Only compare the first and last 64 KiB of each file. Matches are not verified, so this is report-only"
+    )]
+    fast: bool,
+
+    #[arg(
+        long,
+        requires = "fast",
+        help = "Allow --fast together with --symlink or --remove"
+    )]
+    i_know_what_im_doing: bool,
+
+    #[arg(
+        long,
+        help = "Tune the read buffer size to the measured disk throughput"
+    )]
+    adaptive_buffer: bool,
+
+    #[arg(
+        long,
+        help = "Show progress and the estimated time left on stderr (walks the directories twice)"
+    )]
+    progress: bool,
+
+    #[arg(
+        long,
+        help = "Walk the directories twice, first only counting file sizes, to keep files with a unique size out of memory on huge trees"
+    )]
+    two_pass: bool,
+
+    #[arg(
+        long,
+        help = "Compare files of the same size by their full contents right away, skipping the comparison of their first 64 KiB"
+    )]
+    no_short_hash: bool,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        default_value = "sha256",
+        value_parser = parse_algorithm,
+        conflicts_with = "checksum_file",
+        help = "Hash function to compare files by: sha256 or blake3, which is faster on most CPUs (see `dedup bench`)"
+    )]
+    algorithm: HasherFactory,
+
+    #[arg(long, help = "Print statistics about the scan to stderr")]
+    stats: bool,
+
+    #[arg(long, help = "Print reclaimable space per file extension")]
+    stats_by_ext: bool,
+
+    #[arg(
+        long,
+        help = "Print reclaimable space per directory, counting each duplicate in the directory it is in, largest first"
+    )]
+    by_dir: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Print the N largest files that have a duplicate, with their size and number of copies"
+    )]
+    largest_files: Option<usize>,
+
+    #[cfg(feature = "decompress")]
+    #[arg(
+        long,
+        conflicts_with_all = ["mode", "ignore_trailing"],
+        help = "Compare .gz files by their decompressed contents (report only)"
+    )]
+    decompress: bool,
+
+    #[cfg(feature = "decompress")]
+    #[arg(
+        long,
+        conflicts_with_all = ["mode", "dirs"],
+        help = "Report uncompressed files that are the decompressed contents of a .gz file instead of duplicate files"
+    )]
+    compressed_copies: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["mode", "ignore_trailing"],
+        help = "Compare JPEG files without their EXIF, XMP, IPTC and comment segments, so copies that only differ in metadata match (report only)"
+    )]
+    strip_exif: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["mode", "ignore_trailing"],
+        help = "Compare text files, such as source code, with CRLF line endings turned into LF, so Windows and Unix copies match (report only)"
+    )]
+    normalize_eol: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "since",
+        help = "Also compare the resource forks of files (macOS), so files whose forks differ are not duplicates"
+    )]
+    include_forks: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "mode",
+        help = "Report directories with identical contents instead of files"
+    )]
+    dirs: bool,
+
+    #[cfg(feature = "archive")]
+    #[arg(
+        long,
+        conflicts_with_all = ["mode", "dirs"],
+        help = "Report duplicate entries within tar and zip archives instead of duplicate files"
+    )]
+    archive: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["mode", "dirs"],
+        help = "Quickly estimate the reclaimable space by sampling files instead of comparing all of them"
+    )]
+    estimate: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["mode", "dirs"],
+        help = "Report symlinks that point to the same target instead of duplicate files"
+    )]
+    dedup_symlinks: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["mode", "dirs"],
+        help = "Quickly list groups of files with the same size, without reading them; these are candidates, not confirmed duplicates"
+    )]
+    size_only: bool,
+
+    #[cfg(feature = "fuzzy")]
+    #[arg(
+        long,
+        conflicts_with_all = ["mode", "dirs"],
+        help = "Report groups of similar files, such as edited copies of documents, by fuzzy hashing instead of duplicate files"
+    )]
+    similar: bool,
+
+    #[cfg(feature = "fuzzy")]
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 80,
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        requires = "similar",
+        help = "How well files must match to be reported by --similar"
+    )]
+    similarity: u8,
+
+    #[cfg(feature = "cdc")]
+    #[arg(
+        long,
+        conflicts_with_all = ["mode", "dirs"],
+        help = "Estimate how much data a block-level deduplicating file system would share, by content-defined chunking, instead of looking for duplicate files"
+    )]
+    cdc_estimate: bool,
+
+    #[cfg(feature = "remote")]
+    #[arg(
+        long,
+        value_name = "URL",
+        conflicts_with_all = ["replace_by_symlink", "library", "dirs"],
+        help = "Report files whose contents are listed in the JSON manifest at this URL, or remove them with --remove"
+    )]
+    reference_url: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ErrorPolicy::Skip,
+        help = "What to do when a file cannot be read or changed"
+    )]
+    on_error: ErrorPolicy,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Format::Text,
+        help = "Output format of the report"
+    )]
+    format: Format,
+
+    #[arg(
+        long,
+        help = "Instead of the summary, print the number of files, duplicates and bytes saved on one line, separated by tabs"
+    )]
+    terse: bool,
+
+    #[arg(
+        long,
+        help = "In verbose reports, print each kept file once, followed by its duplicates, instead of one line per duplicate"
+    )]
+    report_duplicates_only_once: bool,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Show paths in the report relative to this directory"
+    )]
+    relative_to: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ColorChoice::Auto,
+        help = "Color removed and kept paths in verbose output"
+    )]
+    color: ColorChoice,
+
+    #[arg(skip)]
+    use_color: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Only show the first N hex digits of hashes in the output"
+    )]
+    hash_display_len: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Make the output the same on every run over the same files: walk each directory in sorted order, sort the groups, and leave out progress, colors and timings"
+    )]
+    deterministic: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["mode", "dirs"],
+        help = "Write the hash, size, modification time and path of every file to this file instead of looking for duplicates"
+    )]
+    hash_manifest: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["mode", "dirs", "hash_manifest"],
+        help = "Write the hash of every file to this file in sha256sum format, for use with sha256sum -c, instead of looking for duplicates"
+    )]
+    checksum_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "checksum_file",
+        help = "Mark files as binary ('*') in the --checksum-file, like sha256sum --binary"
+    )]
+    checksum_binary: bool,
+
+    #[arg(
+        long,
+        value_name = "MANIFEST",
+        conflicts_with = "fast",
+        help = "Take the hashes of files whose size and modification time are unchanged from this --hash-manifest of an earlier run instead of reading them in full"
+    )]
+    since: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["since", "fast"],
+        help = "Save the hashes computed so far to this file every minute; run again with the same file to resume an interrupted scan without reading those files again. Removed once the run completes"
+    )]
+    checkpoint: Option<PathBuf>,
+
+    #[arg(
+        long = "hash-cache",
+        id = "hash_cache",
+        value_name = "FILE",
+        num_args = 0..=1,
+        require_equals = true,
+        conflicts_with_all = ["since", "checkpoint", "fast"],
+        help = "Keep the hashes of files read in full in this file, and take the hashes of unchanged files from it; several runs at once may share it. Without FILE, uses a file in the user's cache directory"
+    )]
+    hash_cache_arg: Option<Option<PathBuf>>,
+
+    // The file given with --hash-cache, or the default one
+    #[arg(skip)]
+    hash_cache: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write metrics about the run to this file in the Prometheus text format"
+    )]
+    metrics_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Add the duplicate groups to this JSON catalog, merging them with the groups of earlier runs by hash; several runs at once may share it"
+    )]
+    append_results: Option<PathBuf>,
+
+    #[cfg(feature = "sqlite")]
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write the duplicate groups and their files to this SQLite database, replacing it"
+    )]
+    db: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        help = "Log internal steps to stderr at this level (error, warn, info, debug or trace), or with a RUST_LOG-style filter [default: RUST_LOG, or off]"
+    )]
+    log_level: Option<String>,
+
+    #[arg(
+        long,
+        help = "Read default options from this TOML file [default: .dedup.toml, or config.toml in the user's config directory, if present]"
+    )]
+    config: Option<PathBuf>,
+
+    #[arg(required = true, help = "Directories to search")]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Check that duplicates are detected correctly on this system")]
+    Selftest,
+
+    #[command(about = "Compare the contents of two directory trees, ignoring file names")]
+    Diff {
+        #[arg(
+            short,
+            long,
+            help = "Also list the files of the first tree that exist in the second"
+        )]
+        verbose: bool,
+
+        #[arg(help = "First directory")]
+        left: PathBuf,
+
+        #[arg(help = "Second directory")]
+        right: PathBuf,
+    },
+
+    #[command(about = "Print statistics about files and duplicates, without changing anything")]
+    Stats {
+        #[arg(required = true, help = "Directories to search")]
+        paths: Vec<PathBuf>,
+    },
+
+    #[command(
+        about = "Measure the throughput of the hash functions of --algorithm on a sample of files"
+    )]
+    Bench {
+        #[arg(required = true, help = "Directories to take the sample from")]
+        paths: Vec<PathBuf>,
+    },
+
+    #[command(
+        about = "Hash two files and tell whether they are identical; exits with 1 if they are not"
+    )]
+    Compare {
+        #[arg(help = "First file")]
+        left: PathBuf,
+
+        #[arg(help = "Second file")]
+        right: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Format {
+    /// Human-readable lines
+    Text,
+    /// A single JSON document with the groups, totals and errors
+    Json,
+    /// An XML document with the same contents as the JSON one
+    Xml,
+    /// A Graphviz graph with a node per file, clustered by directory, and
+    /// edges between copies
+    Dot,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ColorChoice {
+    /// Color if standard output is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum KeepStrategy {
+    /// The file that was found first
+    First,
+    /// The file with the most hard links, so that the fewest other references break
+    MostLinked,
+    /// The file modified longest ago (or changed, with --keep-by-ctime)
+    Oldest,
+    /// The file modified most recently (or changed, with --keep-by-ctime)
+    Newest,
+    /// The file with the longest name, which is often the most descriptive one
+    LongestName,
+    /// The file with the shortest name
+    ShortestName,
+}
+
+// Picks the keeper of a group. Symlinks are never kept, as the file they
+// point to might be removed; groups of only symlinks are left alone.
+fn choose_keeper(group: &DuplicateGroup, options: &Options) -> KeeperChoice {
+    let mut choice = if options.first_root_wins {
+        keeper_in_first_root(group, options)
+    } else {
+        preferred_keeper(group, options)
+    };
+    if let Some(dir) = &options.act_under {
+        choice = keeper_outside(choice, group, dir);
+    }
+    if !options.resolve_input_symlinks {
+        return choice;
+    }
+    match choice {
+        KeeperChoice::Keep(i) if group.paths[i].is_symlink() => group
+            .paths
+            .iter()
+            .position(|path| !path.is_symlink())
+            .map_or(KeeperChoice::Skip, KeeperChoice::Keep),
+        choice => choice,
+    }
+}
+
+// With --first-root-wins, the preferred copy among those under the earliest
+// listed directory that has any. Paths in a group start with the directory
+// they were found under, as given.
+fn keeper_in_first_root(group: &DuplicateGroup, options: &Options) -> KeeperChoice {
+    let root_of = |path: &Path| options.paths.iter().position(|root| path.starts_with(root));
+    let first_root = group.paths.iter().filter_map(|path| root_of(path)).min();
+    let candidates: Vec<usize> = (0..group.paths.len())
+        .filter(|&i| root_of(&group.paths[i]) == first_root)
+        .collect();
+    let within = DuplicateGroup {
+        size: group.size,
+        hash: group.hash,
+        paths: candidates.iter().map(|&i| group.paths[i].clone()).collect(),
+    };
+    match preferred_keeper(&within, options) {
+        KeeperChoice::Keep(i) => KeeperChoice::Keep(candidates[i]),
+        KeeperChoice::Skip => KeeperChoice::Skip,
+    }
+}
+
+// With --act-under, a copy outside the directory is kept in favor of the
+// preferred one, so that only copies below it are duplicates
+fn keeper_outside(choice: KeeperChoice, group: &DuplicateGroup, dir: &Path) -> KeeperChoice {
+    match choice {
+        KeeperChoice::Keep(i) if is_below(&group.paths[i], dir) => group
+            .paths
+            .iter()
+            .position(|path| !is_below(path, dir))
+            .map_or(choice, KeeperChoice::Keep),
+        choice => choice,
+    }
+}
+
+fn preferred_keeper(group: &DuplicateGroup, options: &Options) -> KeeperChoice {
+    if let Some(library) = &options.library {
+        if let Some(i) = group.paths.iter().position(|path| is_below(path, library)) {
+            return KeeperChoice::Keep(i);
+        }
+    }
+    if let Some(regex) = &options.keep_matching {
+        if let Some(i) = group
+            .paths
+            .iter()
+            .position(|path| regex.is_match(&path.to_string_lossy()))
+        {
+            return KeeperChoice::Keep(i);
+        }
+    }
+    // Timestamp of the file for oldest/newest, in nanoseconds
+    let time = |path: &Path| {
+        let metadata = fs::symlink_metadata(path).ok()?;
+        let (secs, nsecs) = if options.keep_by_ctime {
+            (metadata.ctime(), metadata.ctime_nsec())
+        } else {
+            (metadata.mtime(), metadata.mtime_nsec())
+        };
+        Some(secs as i128 * 1_000_000_000 + nsecs as i128)
+    };
+    let tolerance = (options.mtime_tolerance * 1e9) as i128;
+    match options.keep {
+        KeepStrategy::First => KeeperChoice::Keep(0),
+        KeepStrategy::MostLinked => keep_max_by_key(group, |path| {
+            fs::symlink_metadata(path).ok().map(|m| m.nlink())
+        }),
+        KeepStrategy::Oldest => {
+            keep_max_by_time(group, |path| time(path).map(|time| -time), tolerance)
+        }
+        KeepStrategy::Newest => keep_max_by_time(group, time, tolerance),
+        KeepStrategy::LongestName => keep_max_by_key(group, name_len),
+        KeepStrategy::ShortestName => keep_max_by_key(group, |path| name_len(path).map(Reverse)),
+    }
+}
+
+// Length of the file name in characters, leaving out the directory
+fn name_len(path: &Path) -> Option<usize> {
+    Some(path.file_name()?.to_string_lossy().chars().count())
+}
+
+// Keeps the file with the greatest key. Ties go to the file found first, and
+// files whose key cannot be read are only kept if no key can be read at all.
+fn keep_max_by_key<K: Ord>(
+    group: &DuplicateGroup,
+    key: impl Fn(&Path) -> Option<K>,
+) -> KeeperChoice {
+    let mut best: Option<(usize, K)> = None;
+    for (i, path) in group.paths.iter().enumerate() {
+        if let Some(key) = key(path) {
+            if best.as_ref().is_none_or(|(_, best_key)| key > *best_key) {
+                best = Some((i, key));
+            }
+        }
+    }
+    KeeperChoice::Keep(best.map_or(0, |(i, _)| i))
+}
+
+// Like `keep_max_by_key`, but keys up to `tolerance` below the greatest count
+// as equal to it, so the first of those files is kept
+fn keep_max_by_time(
+    group: &DuplicateGroup,
+    key: impl Fn(&Path) -> Option<i128>,
+    tolerance: i128,
+) -> KeeperChoice {
+    let keys: Vec<Option<i128>> = group.paths.iter().map(|path| key(path)).collect();
+    let Some(&max) = keys.iter().flatten().max() else {
+        return KeeperChoice::Keep(0);
+    };
+    let first = keys
+        .iter()
+        .position(|key| key.is_some_and(|key| max - key <= tolerance));
+    KeeperChoice::Keep(first.unwrap_or(0))
+}
+
+// Whether `path` is below `dir`, which must be canonical
+fn is_below(path: &Path, dir: &Path) -> bool {
+    path.canonicalize().is_ok_and(|path| path.starts_with(dir))
+}
+
+// Moves `keeper` into `library`, leaving a symlink to its new location, and
+// returns the new path. Does not overwrite existing files in `library`.
+fn move_into_library(keeper: &Path, library: &Path) -> io::Result<PathBuf> {
+    let name = keeper
+        .file_name()
+        .ok_or_else(|| io::Error::other("keeper has no file name"))?;
+    let mut target = library.join(name);
+    let mut n = 1;
+    while target.symlink_metadata().is_ok() {
+        let mut numbered = Path::new(name).file_stem().unwrap_or(name).to_os_string();
+        numbered.push(format!("-{}", n));
+        if let Some(ext) = Path::new(name).extension() {
+            numbered.push(".");
+            numbered.push(ext);
+        }
+        target = library.join(numbered);
+        n += 1;
+    }
+
+    // Computed before the move, while both ends still exist
+    let link = relative_path(keeper, library)?.join(target.file_name().unwrap());
+    match fs::rename(keeper, &target) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(keeper, &target)?;
+            fs::remove_file(keeper)?;
+        }
+        result => result?,
+    }
+    std::os::unix::fs::symlink(link, keeper)?;
+    Ok(target)
+}
+
+// Makes sure the keeper of every group lives in `library`, moving it there
+// if needed, and points the actions of the group at the new location.
+fn consolidate(
+    scan: &mut Scan,
+    library: &Path,
+    options: &Options,
+    left_alone: impl Fn(&DuplicateGroup) -> bool,
+    errors: &mut Vec<SkippedFile>,
+) -> io::Result<()> {
+    let mut keepers: HashMap<usize, Option<PathBuf>> = HashMap::new();
+    for action in &scan.actions {
+        if keepers.contains_key(&action.group) {
+            continue;
+        }
+        // Groups that are left alone are skipped later on, without moving
+        // anything
+        let keeper = if is_below(&action.keeper, library) || left_alone(&scan.groups[action.group])
+        {
+            Some(action.keeper.clone())
+        } else if !options.writable.is_empty()
+            && !options
+                .writable
+                .iter()
+                .any(|dir| is_below(&action.keeper, dir))
+        {
+            eprintln!(
+                "Warning: not moving {:?} into the library, it is outside the --writable directories",
+                action.keeper
+            );
+            None
+        } else {
+            match move_into_library(&action.keeper, library) {
+                Ok(target) => {
+                    if options.verbose && options.format == Format::Text {
+                        println!("move {:?} -> {:?}", action.keeper, target);
+                    }
+                    Some(target)
+                }
+                Err(err) => {
+                    let skipped = SkippedFile::new(&action.keeper, &err);
+                    options.on_error.handle(&action.keeper, err)?;
+                    errors.push(skipped);
+                    None
+                }
+            }
+        };
+        keepers.insert(action.group, keeper);
+    }
+
+    // Groups whose keeper could not be moved are left alone
+    scan.actions
+        .retain(|action| keepers[&action.group].is_some());
+    for action in &mut scan.actions {
+        action.keeper = keepers[&action.group].clone().unwrap();
+    }
+    Ok(())
+}
+
+fn init_logging(level: Option<&str>) -> anyhow::Result<()> {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(level).context("Invalid --log-level")?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("off")),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .init();
+    Ok(())
+}
+
+// Parses a size in bytes with an optional binary suffix, e.g. "512", "64K",
+// "10GiB"
+fn parse_size(arg: &str) -> Result<u64, String> {
+    let arg = arg.trim();
+    let digits = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let (number, suffix) = arg.split_at(digits);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", arg))?;
+    let shift = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("invalid size suffix {:?}", suffix)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {:?} is too large", arg))
+}
+
+// Parses an EXT=SIZE pair of --min-size-for
+fn parse_min_size_for(arg: &str) -> Result<(String, u64), String> {
+    let (ext, size) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected EXT=SIZE, got {:?}", arg))?;
+    Ok((ext.trim().to_string(), parse_size(size)?))
+}
+
+// Looks up the hash function of --algorithm by name
+fn parse_algorithm(arg: &str) -> Result<HasherFactory, String> {
+    HasherFactory::by_name(arg).ok_or_else(|| {
+        let names: Vec<&str> = HasherFactory::builtin()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        format!(
+            "unknown algorithm {:?}, expected one of {}",
+            arg,
+            names.join(", ")
+        )
+    })
+}
+
+// Reads the hashes at the start of each line, skipping blank lines and
+// comments starting with '#'. Anything after the hash, such as the path in a
+// manifest or sha256sum file, is ignored.
+fn read_hash_list(path: &Path) -> anyhow::Result<HashSet<Hash>> {
+    let contents = fs::read_to_string(path)?;
+    let mut hashes = HashSet::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let hex = line.split_whitespace().next().unwrap_or_default();
+        // sha256sum starts lines with escaped names with a backslash
+        let hex = hex.strip_prefix('\\').unwrap_or(hex);
+        let hash = from_hex(hex)
+            .with_context(|| format!("Invalid hash on line {}: {:?}", number + 1, hex))?;
+        hashes.insert(hash);
+    }
+    Ok(hashes)
+}
+
+fn format_bytes(num: u64) -> String {
+    match NumberPrefix::binary(num as f64) {
+        NumberPrefix::Standalone(bytes) => {
+            format!("{} bytes", bytes)
+        }
+        NumberPrefix::Prefixed(prefix, n) => {
+            format!("{:.1} {}B", n, prefix)
+        }
+    }
+}
+
+// Width of the size column in verbose output, enough for "1023.9 KiB"
+// and "65535 bytes"
+const SIZE_WIDTH: usize = 11;
+
+const RED: &str = "31";
+const GREEN: &str = "32";
+
+// Wraps `text` in the ANSI escape sequence for the given color, if enabled
+fn paint(text: String, color: &str, options: &Options) -> String {
+    if options.use_color {
+        format!("\x1b[{}m{}\x1b[0m", color, text)
+    } else {
+        text
+    }
+}
+
+static WARNED_OUTSIDE_BASE: AtomicBool = AtomicBool::new(false);
+
+// Returns `path` as it should appear in reports: relative to --relative-to
+// if given, or else unchanged. Paths outside that directory are shown
+// absolute, with a warning the first time.
+fn display_path<'a>(path: &'a Path, options: &Options) -> Cow<'a, Path> {
+    let Some(base) = &options.relative_to else {
+        return Cow::Borrowed(path);
+    };
+    let Ok(absolute) = std::path::absolute(path) else {
+        return Cow::Borrowed(path);
+    };
+    match absolute.strip_prefix(base) {
+        Ok(relative) => Cow::Owned(relative.to_path_buf()),
+        Err(_) => {
+            if !WARNED_OUTSIDE_BASE.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "Warning: {:?} is outside {:?}, showing absolute paths for such files",
+                    path, base
+                );
+            }
+            Cow::Owned(absolute)
+        }
+    }
+}
+
+fn format_hash(hash: &Hash, options: &Options) -> String {
+    let mut hex = to_hex(hash);
+    if let Some(len) = options.hash_display_len {
+        hex.truncate(len);
+    }
+    hex
+}
+
+fn report_duplicate_dirs(options: &Options) -> anyhow::Result<()> {
+    let groups = find_duplicate_dirs(&options.paths, &filter_options(options))?;
+
+    let mut num_dirs = 0;
+    let mut saved_bytes = 0;
+    for group in &groups {
+        let extra = group.paths.len() as u64 - 1;
+        println!(
+            "({}) {} identical directories, hash {}:",
+            format_bytes(group.size),
+            group.paths.len(),
+            format_hash(&group.hash, options)
+        );
+        for path in &group.paths {
+            println!("  {:?}", display_path(path, options));
+        }
+        num_dirs += extra;
+        saved_bytes += extra * group.size;
+    }
+
+    println!(
+        "Found {} duplicate directories. Removing them would save {}.",
+        num_dirs,
+        format_bytes(saved_bytes)
+    );
+    Ok(())
+}
+
+#[cfg(feature = "archive")]
+fn report_archive_duplicates(options: &Options) -> anyhow::Result<()> {
+    let scan_options = filter_options(options);
+    let archives = dedup::archive::find_archive_duplicates(&options.paths, &scan_options)?;
+
+    let mut num_entries = 0;
+    let mut saved_bytes = 0;
+    for archive in &archives {
+        println!("{:?}:", archive.archive);
+        for group in &archive.groups {
+            let extra = group.paths.len() as u64 - 1;
+            println!(
+                "  ({}) {} identical entries, hash {}:",
+                format_bytes(group.size),
+                group.paths.len(),
+                format_hash(&group.hash, options)
+            );
+            for path in &group.paths {
+                println!("    {:?}", path);
+            }
+            num_entries += extra;
+            saved_bytes += extra * group.size;
+        }
+    }
+    println!(
+        "Found {} duplicate entries in {} archives, taking {} uncompressed.",
+        num_entries,
+        archives.len(),
+        format_bytes(saved_bytes)
+    );
+    Ok(())
+}
+
+// Number of size buckets sampled by --estimate
+const ESTIMATE_BUCKETS: usize = 1000;
+
+fn report_estimate(options: &Options) -> anyhow::Result<()> {
+    let scan_options = filter_options(options);
+    let estimate = estimate_savings(&options.paths, &scan_options, ESTIMATE_BUCKETS)?;
+
+    println!(
+        "{} files, of which {} ({}) share their size with another file.",
+        estimate.num_files,
+        estimate.candidate_files,
+        format_bytes(estimate.candidate_bytes)
+    );
+    println!(
+        "Estimated reclaimable space: {}.",
+        format_bytes(estimate.estimated_reclaimable)
+    );
+    if estimate.candidate_bytes > 0 {
+        println!(
+            "This is extrapolated from {} files in size groups covering {:.1}% of those bytes, compared by their first and last 64 KiB only; a full scan may find less.",
+            estimate.hashed_files,
+            estimate.sampled_candidate_bytes as f64 * 100.0 / estimate.candidate_bytes as f64
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "remote")]
+fn report_reference_matches(url: &str, options: &Options) -> anyhow::Result<()> {
+    let reference = remote::fetch_reference(url)?;
+    let scan_options = filter_options(options);
+    let matches = find_known_files(&options.paths, &scan_options, &reference)?;
+
+    let remove = options.remove && !options.dry_run;
+    let mut num_files = 0;
+    let mut total_bytes = 0;
+    for file in &matches {
+        if remove {
+            if let Err(err) = fs::remove_file(&file.path) {
+                options.on_error.handle(&file.path, err)?;
+                continue;
+            }
+        }
+        if options.verbose || !remove {
+            let verb = match (options.remove, remove) {
+                (true, true) => "remove",
+                (true, false) => "would remove",
+                (false, _) => "in reference",
+            };
+            println!(
+                "{:>SIZE_WIDTH$}  {} {:?} (hash {})",
+                format_bytes(file.size),
+                verb,
+                display_path(&file.path, options),
+                format_hash(&file.hash, options)
+            );
+        }
+        num_files += 1;
+        total_bytes += file.size;
+    }
+    if remove {
+        println!(
+            "Removed {} files whose contents are in the reference manifest, saving {}.",
+            num_files,
+            format_bytes(total_bytes)
+        );
+    } else {
+        println!(
+            "Found {} files whose contents are in the reference manifest, taking {}.",
+            num_files,
+            format_bytes(total_bytes)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "fuzzy")]
+fn report_similar_files(options: &Options) -> anyhow::Result<()> {
+    let scan_options = filter_options(options);
+    let groups =
+        dedup::similar::find_similar_files(&options.paths, &scan_options, options.similarity)?;
+
+    for group in &groups {
+        println!(
+            "{} similar files, matching at least {}%:",
+            group.paths.len(),
+            group.min_score
+        );
+        for path in &group.paths {
+            println!("  {:?}", display_path(path, options));
+        }
+    }
+    println!("Found {} groups of similar files.", groups.len());
+    Ok(())
+}
+
+#[cfg(feature = "cdc")]
+fn report_chunk_estimate(options: &Options) -> anyhow::Result<()> {
+    let scan_options = filter_options(options);
+    let estimate = dedup::cdc::estimate_chunk_savings(&options.paths, &scan_options)?;
+
+    let percent = |bytes: u64| bytes as f64 * 100.0 / estimate.total_bytes.max(1) as f64;
+    println!(
+        "{} files, {} in {} chunks, of which {} are distinct.",
+        estimate.num_files,
+        format_bytes(estimate.total_bytes),
+        estimate.num_chunks,
+        estimate.unique_chunks
+    );
+    println!(
+        "{:.1}% of bytes are shared chunks: block-level deduplication would save {}.",
+        percent(estimate.shared_chunk_bytes),
+        format_bytes(estimate.shared_chunk_bytes)
+    );
+    println!(
+        "{:.1}% of bytes are in duplicate files: file-level deduplication would save {}.",
+        percent(estimate.duplicate_file_bytes),
+        format_bytes(estimate.duplicate_file_bytes)
+    );
+    Ok(())
+}
+
+fn report_same_size_files(options: &Options) -> anyhow::Result<()> {
+    let scan_options = filter_options(options);
+    let groups = find_same_size_files(&options.paths, &scan_options)?;
+
+    let mut num_files = 0;
+    let mut upper_bound = 0;
+    for group in &groups {
+        println!(
+            "{} files of {} each:",
+            group.paths.len(),
+            format_bytes(group.size)
+        );
+        for path in &group.paths {
+            println!("  {:?}", display_path(path, options));
+        }
+        num_files += group.paths.len() as u64;
+        upper_bound += (group.paths.len() as u64 - 1) * group.size;
+    }
+    println!(
+        "Found {} files in {} groups of the same size. These are candidates, not confirmed duplicates: at most {} could be reclaimed.",
+        num_files,
+        groups.len(),
+        format_bytes(upper_bound)
+    );
+    Ok(())
+}
+
+#[cfg(feature = "decompress")]
+fn report_compressed_copies(options: &Options) -> anyhow::Result<()> {
+    let scan_options = filter_options(options);
+    let copies = dedup::find_compressed_copies(&options.paths, &scan_options)?;
+
+    let mut total = 0;
+    for copy in &copies {
+        println!(
+            "{:>SIZE_WIDTH$}  {:?} is the contents of {:?}",
+            format_bytes(copy.size),
+            display_path(&copy.plain, options),
+            display_path(&copy.compressed, options)
+        );
+        total += copy.size;
+    }
+    println!(
+        "Found {} uncompressed files with a compressed copy. Removing them would save {}.",
+        copies.len(),
+        format_bytes(total)
+    );
+    Ok(())
+}
+
+fn report_duplicate_symlinks(options: &Options) -> anyhow::Result<()> {
+    let scan_options = filter_options(options);
+    let groups = find_duplicate_symlinks(&options.paths, &scan_options)?;
+
+    let mut num_links = 0;
+    for group in &groups {
+        println!("{} symlinks to {:?}:", group.links.len(), group.target);
+        for link in &group.links {
+            println!("  {:?}", link);
+        }
+        num_links += group.links.len() - 1;
+    }
+    println!("Found {} redundant symlinks.", num_links);
+    Ok(())
+}
+
+fn report_diff(left: &Path, right: &Path, verbose: bool) -> anyhow::Result<()> {
+    let diff = diff_trees(left, right, &ScanOptions::default())?;
+
+    println!("Only in {:?}:", left);
+    for path in &diff.left_only {
+        println!("  {:?}", path);
+    }
+    println!("Only in {:?}:", right);
+    for path in &diff.right_only {
+        println!("  {:?}", path);
+    }
+    if verbose {
+        println!("Also in {:?}:", right);
+        for path in &diff.shared {
+            println!("  {:?}", path);
+        }
+    }
+
+    println!(
+        "{} files ({}) only in {:?}, {} files ({}) only in {:?}, {} files ({}) in both.",
+        diff.left_only.len(),
+        format_bytes(diff.left_only_bytes),
+        left,
+        diff.right_only.len(),
+        format_bytes(diff.right_only_bytes),
+        right,
+        diff.shared.len(),
+        format_bytes(diff.shared_bytes)
+    );
+    Ok(())
+}
+
+// Upper bounds of the size classes in the size distribution
+const SIZE_CLASSES: [u64; 5] = [1 << 10, 64 << 10, 1 << 20, 64 << 20, 1 << 30];
+
+// Limits of the sample read into memory by `dedup bench`
+const BENCH_FILES: usize = 32;
+const BENCH_BYTES: u64 = 256 << 20;
+// Each hash function is run over the sample until this much time has passed
+const BENCH_TIME: Duration = Duration::from_secs(1);
+
+fn run_bench(paths: &[PathBuf]) -> anyhow::Result<()> {
+    let mut sample = Vec::new();
+    let mut sample_bytes = 0;
+    'roots: for root in paths {
+        for entry in walkdir::WalkDir::new(root).sort_by_file_name() {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let size = entry.metadata()?.len();
+            if size == 0 || sample_bytes + size > BENCH_BYTES {
+                continue;
+            }
+            sample.push(fs::read(entry.path())?);
+            sample_bytes += size;
+            if sample.len() == BENCH_FILES {
+                break 'roots;
+            }
+        }
+    }
+    if sample.is_empty() {
+        anyhow::bail!("No files to measure with");
+    }
+    println!(
+        "Hashing {} files, {} in total, from memory:",
+        sample.len(),
+        format_bytes(sample_bytes)
+    );
+
+    println!("{:<10} {:>14}", "Algorithm", "Throughput");
+    for (name, factory) in HasherFactory::builtin() {
+        let start = Instant::now();
+        let mut hashed = 0;
+        while start.elapsed() < BENCH_TIME {
+            for contents in &sample {
+                std::hint::black_box(factory.hash_bytes(contents));
+            }
+            hashed += sample_bytes;
+        }
+        let rate = hashed as f64 / start.elapsed().as_secs_f64();
+        println!("{:<10} {:>12}/s", name, format_bytes(rate as u64));
+    }
+    println!("Files are compared by the hash function chosen with --algorithm NAME.");
+    Ok(())
+}
+
+// Prints the size and hashes of both files, the way a scan compares them.
+// Returns whether they are identical.
+fn run_compare(left: &Path, right: &Path) -> anyhow::Result<bool> {
+    let mut found = Vec::new();
+    for path in [left, right] {
+        let size = fs::metadata(path)
+            .with_context(|| format!("Failed to read {:?}", path))?
+            .len();
+        let short = fs::File::open(path)
+            .and_then(short_hash)
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        let full = hash_file(path).with_context(|| format!("Failed to read {:?}", path))?;
+        println!("{}", path.display());
+        println!("  Size:       {} ({} bytes)", format_bytes(size), size);
+        println!("  Short hash: {}", to_hex(&short));
+        println!("  Full hash:  {}", to_hex(&full));
+        found.push((size, short, full));
+    }
+    let ((left_size, left_short, left_full), (right_size, right_short, right_full)) =
+        (found[0], found[1]);
+    if left_size != right_size {
+        println!("Different: the sizes differ.");
+    } else if left_short != right_short {
+        println!("Different: the first 64 KiB differ.");
+    } else if left_full != right_full {
+        println!("Different: the first 64 KiB match, but the rest differs.");
+    } else {
+        println!("Identical.");
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+fn report_stats(paths: &[PathBuf]) -> anyhow::Result<()> {
+    let scan = find_duplicates(paths, &ScanOptions::default(), |_| KeeperChoice::Keep(0))?;
+
+    let total_bytes: u64 = scan
+        .file_sizes
+        .iter()
+        .map(|(size, count)| size * count)
+        .sum();
+    let reclaimable: u64 = scan.actions.iter().map(|action| action.size).sum();
+    println!("{:<20} {}", "Files:", scan.num_files);
+    println!("{:<20} {}", "Total size:", format_bytes(total_bytes));
+    println!("{:<20} {}", "Duplicate groups:", scan.groups.len());
+    println!("{:<20} {}", "Duplicate files:", scan.actions.len());
+    println!("{:<20} {}", "Reclaimable:", format_bytes(reclaimable));
+
+    let mut classes = [(0u64, 0u64); SIZE_CLASSES.len() + 1];
+    for (&size, &count) in &scan.file_sizes {
+        let class = SIZE_CLASSES
+            .iter()
+            .position(|&limit| size < limit)
+            .unwrap_or(SIZE_CLASSES.len());
+        classes[class].0 += count;
+        classes[class].1 += size * count;
+    }
+    println!("Size distribution:");
+    for (i, (count, bytes)) in classes.iter().enumerate() {
+        let label = match SIZE_CLASSES.get(i) {
+            Some(&limit) => format!("< {}", format_bytes(limit)),
+            None => format!(">= {}", format_bytes(SIZE_CLASSES[i - 1])),
+        };
+        println!(
+            "  {:<14} {:>10} files {:>12}",
+            label,
+            count,
+            format_bytes(*bytes)
+        );
+    }
+    Ok(())
+}
+
+// The transform for --strip-exif and --normalize-eol, if either is given
+fn normalizer(options: &Options) -> Option<Normalizer> {
+    let mut normalizers = Vec::new();
+    if options.strip_exif {
+        normalizers.push(Normalizer::strip_exif());
+    }
+    if options.normalize_eol {
+        normalizers.push(Normalizer::normalize_eol());
+    }
+    match normalizers.len() {
+        0 | 1 => normalizers.pop(),
+        _ => Some(Normalizer::first_of(normalizers)),
+    }
+}
+
+// Files written by this run, which must not be scanned if they are in one of
+// the searched directories. Files written in place of another are included.
+fn own_output_files(options: &Options) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = [
+        &options.hash_manifest,
+        &options.checksum_file,
+        &options.emit_script,
+        &options.plan_json,
+    ]
+    .into_iter()
+    .flatten()
+    .cloned()
+    .collect();
+    #[cfg(feature = "sqlite")]
+    let replaced = [
+        &options.metrics_file,
+        &options.checkpoint,
+        &options.hash_cache,
+        &options.append_results,
+        &options.db,
+    ];
+    #[cfg(not(feature = "sqlite"))]
+    let replaced = [
+        &options.metrics_file,
+        &options.checkpoint,
+        &options.hash_cache,
+        &options.append_results,
+    ];
+    for path in replaced.into_iter().flatten() {
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        files.push(path.clone());
+        files.push(tmp_path.into());
+    }
+    // Files shared between runs are written under a lock file
+    let shared = [
+        &options.checkpoint,
+        &options.hash_cache,
+        &options.append_results,
+    ];
+    for path in shared.into_iter().flatten() {
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        files.push(lock_path.into());
+    }
+    files
+}
+
+// The options that decide which files are scanned and how they are hashed,
+// shared by every mode
+fn filter_options(options: &Options) -> ScanOptions {
+    ScanOptions {
+        min_size: options.min_size,
+        min_size_for: options.min_size_for.clone(),
+        max_depth: options.max_depth,
+        one_file_system: options.one_file_system,
+        extensions: options.extensions.clone(),
+        exclude: options.exclude.clone(),
+        skip_files: own_output_files(options),
+        case_sensitive: options.case_sensitive,
+        resolve_input_symlinks: options.resolve_input_symlinks,
+        on_error: options.on_error,
+        hasher: options.algorithm.clone(),
+        ..ScanOptions::default()
+    }
+}
+
+// Quotes `arg` for a POSIX shell: within single quotes, every byte stands for
+// itself except the single quote, which ends the quoted part
+fn shell_quote(arg: &std::ffi::OsStr) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &byte in arg.as_bytes() {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+// Fills in the placeholders of the --exec command for `action`. Paths are
+// quoted, so the command can use them as single arguments.
+fn exec_command(template: &str, action: &Action, group: &DuplicateGroup) -> Vec<u8> {
+    let placeholders = [
+        ("{dup}", shell_quote(action.duplicate.as_os_str())),
+        ("{keeper}", shell_quote(action.keeper.as_os_str())),
+        ("{size}", action.size.to_string().into_bytes()),
+        ("{hash}", to_hex(&group.hash).into_bytes()),
+    ];
+    let mut command = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        command.extend_from_slice(&rest.as_bytes()[..start]);
+        rest = &rest[start..];
+        match placeholders.iter().find(|(name, _)| rest.starts_with(name)) {
+            Some((name, value)) => {
+                command.extend_from_slice(value);
+                rest = &rest[name.len()..];
+            }
+            None => {
+                command.push(b'{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    command.extend_from_slice(rest.as_bytes());
+    command
+}
+
+fn run_command(command: &[u8]) -> io::Result<()> {
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(std::ffi::OsStr::from_bytes(command))
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "--exec command {:?} failed ({})",
+            String::from_utf8_lossy(command),
+            status
+        )));
+    }
+    Ok(())
+}
+
+// Writes the commands for --emit-script as an executable script, which runs
+// them from the current directory as relative paths are relative to it
+fn write_script(path: &Path, stats: &Stats) -> io::Result<()> {
+    let mut script = b"#!/bin/sh\n".to_vec();
+    writeln!(
+        script,
+        "# Written by dedup for {} duplicates, saving {}.",
+        stats.num_actions,
+        format_bytes(stats.saved_bytes)
+    )?;
+    script.extend_from_slice(b"set -e\ncd -- ");
+    script.extend_from_slice(&shell_quote(std::env::current_dir()?.as_os_str()));
+    script.push(b'\n');
+    for command in &stats.script {
+        script.extend_from_slice(command);
+        script.push(b'\n');
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o755)
+        .open(path)?;
+    file.write_all(&script)
+}
+
+fn write_manifest(path: &Path, format: ManifestFormat, options: &Options) -> anyhow::Result<()> {
+    let scan_options = filter_options(options);
+    let file = fs::File::create(path)
+        .with_context(|| format!("Failed to create hash manifest {:?}", path))?;
+    let num_files = write_hash_manifest(
+        &options.paths,
+        &scan_options,
+        format,
+        io::BufWriter::new(file),
+    )
+    .with_context(|| format!("Failed to write hash manifest {:?}", path))?;
+    println!("Wrote hashes of {} files to {:?}.", num_files, path);
+    Ok(())
+}
+
+fn print_summary(scan: &Scan, stats: &Stats, options: &Options) {
+    print!("Processed {} files. ", scan.num_files);
+    if options.resolve_input_symlinks {
+        print!(
+            "Compared {} symlinks by the contents of their targets. ",
+            scan.resolved_symlinks
+        );
+    }
+    if options.one_file_system {
+        print!(
+            "Skipped {} subtrees on other file systems. ",
+            scan.skipped_subtrees
+        );
+    }
+    if options.remove || options.replace_by_symlink {
+        if options.dry_run {
+            if options.remove {
+                print!("Would remove {} files", stats.num_actions);
+            } else {
+                print!("Would replace {} files by symlinks", stats.num_actions);
+            }
+        } else if options.remove {
+            print!("Removed {} files", stats.num_actions);
+        } else {
+            /* if options.replace_by_symlink  */
+            print!("Created {} symlinks", stats.num_actions);
+        }
+        let on_disk = if options.remove {
+            stats.savings.remove
+        } else {
+            stats.savings.symlink
+        };
+        println!(
+            ", saving {} ({} on disk).",
+            format_bytes(stats.saved_bytes),
+            format_bytes(on_disk)
+        );
+        if stats.pruned_dirs > 0 {
+            println!("Pruned {} empty directories.", stats.pruned_dirs);
+        }
+        if stats.skipped_multilinked > 0 {
+            println!(
+                "Skipped {} files with other hard links.",
+                stats.skipped_multilinked
+            );
+        }
+        if stats.failed_link_checks > 0 {
+            println!(
+                "Skipped {} files whose link would not lead to the kept file.",
+                stats.failed_link_checks
+            );
+        }
+        if stats.skipped_readonly > 0 {
+            println!(
+                "Skipped {} files outside the --writable directories.",
+                stats.skipped_readonly
+            );
+        }
+        if stats.protected_groups > 0 {
+            println!(
+                "Left {} groups alone whose hash is listed in --keep-hashes.",
+                stats.protected_groups
+            );
+        }
+        if stats.small_groups > 0 {
+            println!(
+                "Only reported {} groups with fewer than {} copies.",
+                stats.small_groups, options.min_dupes_to_act
+            );
+        }
+        if stats.beyond_free_target > 0 {
+            println!(
+                "Only reported {} more duplicates ({}) once the --free-target was met.",
+                stats.beyond_free_target,
+                format_bytes(stats.beyond_free_target_bytes)
+            );
+        }
+        if stats.skipped_groups > 0 {
+            println!(
+                "Skipped {} groups because the kept file could no longer be read or, with --verify-all, the copies differed.",
+                stats.skipped_groups
+            );
+        }
+    } else {
+        println!(
+            "Found {} duplicates. Removing them would save {}.",
+            stats.num_actions,
+            format_bytes(stats.saved_bytes)
+        );
+        println!(
+            "Space freed on disk by removing them: {}, by hard links or reflinks: {}, by symlinks: {}.",
+            format_bytes(stats.savings.remove),
+            format_bytes(stats.savings.link),
+            format_bytes(stats.savings.symlink)
+        );
+    }
+    if let Some(dir) = &options.act_under {
+        println!(
+            "Only duplicates below {:?} were counted; {} copies elsewhere were left alone.",
+            display_path(dir, options),
+            stats.outside_act_under
+        );
+    }
+}
+
+fn print_error_summary(errors: &[&SkippedFile], options: &Options) {
+    if errors.is_empty() {
+        return;
+    }
+    let mut by_kind: BTreeMap<String, u64> = BTreeMap::new();
+    for skipped in errors {
+        *by_kind.entry(skipped.kind.to_string()).or_default() += 1;
+    }
+    println!("Skipped {} files because of errors:", errors.len());
+    for (kind, count) in by_kind {
+        println!("  {:>6}  {}", count, kind);
+    }
+    if options.verbose {
+        for skipped in errors {
+            println!(
+                "  {:?}: {}",
+                display_path(&skipped.path, options),
+                skipped.message
+            );
+        }
+    }
+}
 
-    #[arg(
-        short = 's',
-        long = "symlink",
-        group = "mode",
-        help = "Replace duplicate files by symlinks"
-    )]
-    replace_by_symlink: bool,
+// Builds the report printed by --format json and xml
+// Orders the groups by their first path, and the actions by group, keeping
+// the order of the duplicates within each group
+fn sort_groups(scan: &mut Scan) {
+    let mut order: Vec<usize> = (0..scan.groups.len()).collect();
+    order.sort_by(|&a, &b| scan.groups[a].paths[0].cmp(&scan.groups[b].paths[0]));
+    let mut position = vec![0; order.len()];
+    for (new, &old) in order.iter().enumerate() {
+        position[old] = new;
+    }
+    let mut groups: Vec<Option<DuplicateGroup>> = std::mem::take(&mut scan.groups)
+        .into_iter()
+        .map(Some)
+        .collect();
+    scan.groups = order
+        .iter()
+        .map(|&old| groups[old].take().unwrap())
+        .collect();
+    for action in &mut scan.actions {
+        action.group = position[action.group];
+    }
+    scan.actions.sort_by_key(|action| action.group);
+}
 
-    #[arg(long, group = "mode", help = "Remove duplicate files")]
-    remove: bool,
+// Merges `groups` into the catalog at `path`. Runs sharing the catalog take
+// turns through an exclusive lock on `<path>.lock`, held from reading the
+// catalog until the merged one replaces it by a rename.
+fn append_results(path: &Path, groups: &[DuplicateGroup]) -> anyhow::Result<()> {
+    let with_suffix = |suffix: &str| {
+        let mut path = path.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    let lock = fs::File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(with_suffix(".lock"))?;
+    lock.lock()?;
+    let mut catalog: json::Catalog = match fs::read(path) {
+        Ok(contents) => serde_json::from_slice(&contents)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => json::Catalog::default(),
+        Err(err) => return Err(err.into()),
+    };
+    for group in groups {
+        let entry = catalog
+            .groups
+            .entry(to_hex(&group.hash))
+            .or_insert_with(|| json::CatalogEntry {
+                size: group.size,
+                paths: BTreeSet::new(),
+            });
+        for path in &group.paths {
+            // Runs may search different trees from different directories
+            let path = std::path::absolute(path)?;
+            entry.paths.insert(json::path_string(&path));
+        }
+    }
+    let temp = with_suffix(".tmp");
+    let mut out = io::BufWriter::new(fs::File::create(&temp)?);
+    serde_json::to_writer_pretty(&mut out, &catalog)?;
+    writeln!(out)?;
+    out.into_inner()?.sync_all()?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
 
-    #[arg(required = true, help = "Directories to search")]
-    paths: Vec<PathBuf>,
+// The actions that will be attempted, leaving out groups that are left alone
+// regardless of what happens to the others
+fn build_plan(scan: &Scan, options: &Options, keep_hashes: &HashSet<Hash>) -> json::Plan {
+    let operation = if options.remove {
+        "remove"
+    } else if options.replace_by_symlink {
+        "symlink"
+    } else {
+        "none"
+    };
+    let actions = scan
+        .actions
+        .iter()
+        .filter(|action| {
+            let group = &scan.groups[action.group];
+            !keep_hashes.contains(&group.hash)
+                && (operation == "none" || group.paths.len() as u64 >= options.min_dupes_to_act)
+        })
+        .map(|action| json::PlannedAction {
+            operation,
+            size: action.size,
+            hash: to_hex(&scan.groups[action.group].hash),
+            duplicate: json::path_string(&action.duplicate),
+            raw_duplicate: json::raw_path(&action.duplicate),
+            keeper: json::path_string(&action.keeper),
+            raw_keeper: json::raw_path(&action.keeper),
+            link_target: options
+                .replace_by_symlink
+                .then(|| relative_path(&action.duplicate, &action.keeper).ok())
+                .flatten()
+                .map(|target| json::path_string(&target)),
+        })
+        .collect();
+    json::Plan {
+        version: env!("CARGO_PKG_VERSION"),
+        arguments: std::env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect(),
+        dry_run: options.dry_run,
+        actions,
+    }
 }
 
-type Hash = GenericArray<u8, sha2::digest::consts::U32>;
+fn build_report(
+    scan: &Scan,
+    stats: &Stats,
+    errors: &[&SkippedFile],
+    options: &Options,
+) -> json::Report {
+    json::Report {
+        files_processed: scan.num_files,
+        duplicates: stats.num_actions,
+        saved_bytes: stats.saved_bytes,
+        groups: scan
+            .groups
+            .iter()
+            .map(|group| {
+                let shown: Vec<Cow<Path>> = group
+                    .paths
+                    .iter()
+                    .map(|path| display_path(path, options))
+                    .collect();
+                json::Group {
+                    size: group.size,
+                    hash: format_hash(&group.hash, options),
+                    paths: shown.iter().map(|path| json::path_string(path)).collect(),
+                    raw_paths: shown
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(i, path)| Some((i, json::raw_path(path)?)))
+                        .collect(),
+                }
+            })
+            .collect(),
+        errors: errors
+            .iter()
+            .map(|skipped| json::Error {
+                path: json::path_string(&display_path(&skipped.path, options)),
+                raw_path: json::raw_path(&display_path(&skipped.path, options)),
+                kind: skipped.kind.to_string(),
+                message: skipped.message.clone(),
+            })
+            .collect(),
+    }
+}
 
-#[derive(Debug)]
-enum SizeMapEntry {
-    One(PathBuf),
-    Multiple(MultiMap<Hash, PathBuf>),
+#[cfg(feature = "sqlite")]
+fn write_db(path: &Path, scan: &Scan, stats: &Stats, options: &Options) -> anyhow::Result<()> {
+    let groups: Vec<db::Group> = scan
+        .groups
+        .iter()
+        .map(|group| db::Group {
+            size: group.size,
+            hash: to_hex(&group.hash),
+            files: group
+                .paths
+                .iter()
+                .map(|path| {
+                    let shown = display_path(path, options);
+                    db::File {
+                        path: json::path_string(&shown),
+                        dir: json::path_string(shown.parent().unwrap_or(Path::new(""))),
+                        extension: extension_of(path),
+                        kept: !stats.replaced.contains(path),
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+    db::write(path, &groups)
 }
 
-struct Index {
-    size_map: BTreeMap<u64, SizeMapEntry>,
-    full_hashes: HashMap<PathBuf, Hash>,
+// Quotes a string as a DOT identifier
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
 }
 
-fn short_hash(path: &Path) -> io::Result<Hash> {
-    let mut hasher = Sha256::new();
-    let mut file = std::fs::File::open(path)?;
-    let mut buf = [0u8; HASH_BLOCK_LEN];
-    let mut total_read: usize = 0;
+// Prints a graph with a node per file, with the files of each directory in a
+// cluster, and edges from the first file of each group to its copies
+fn print_dot(groups: &[DuplicateGroup], options: &Options) -> io::Result<()> {
+    let mut dirs: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for path in groups.iter().flat_map(|group| &group.paths) {
+        let path = display_path(path, options);
+        let dir = path.parent().unwrap_or(Path::new(""));
+        dirs.entry(json::path_string(dir))
+            .or_default()
+            .insert(json::path_string(&path));
+    }
 
-    while total_read < HASH_BLOCK_LEN {
-        let read_bytes = file.read(&mut buf[total_read..])?;
-        if read_bytes == 0 {
-            break;
+    let mut out = io::stdout().lock();
+    writeln!(out, "graph duplicates {{")?;
+    writeln!(out, "    node [shape=box];")?;
+    for (i, (dir, paths)) in dirs.iter().enumerate() {
+        writeln!(out, "    subgraph cluster_{} {{", i)?;
+        writeln!(out, "        label={};", dot_quote(dir))?;
+        for path in paths {
+            let name = Path::new(path).file_name().unwrap_or_default();
+            writeln!(
+                out,
+                "        {} [label={}];",
+                dot_quote(path),
+                dot_quote(&name.to_string_lossy())
+            )?;
         }
-        total_read += read_bytes;
+        writeln!(out, "    }}")?;
     }
-
-    hasher.update(buf);
-    let mut hash = Hash::default();
-    hasher.finalize_into(&mut hash);
-    Ok(hash)
+    for group in groups {
+        let first = json::path_string(&display_path(&group.paths[0], options));
+        for path in &group.paths[1..] {
+            writeln!(
+                out,
+                "    {} -- {} [label={}];",
+                dot_quote(&first),
+                dot_quote(&json::path_string(&display_path(path, options))),
+                dot_quote(&format_bytes(group.size))
+            )?;
+        }
+    }
+    writeln!(out, "}}")
 }
 
-fn compute_full_hash(path: &Path) -> io::Result<Hash> {
-    let mut hasher = Sha256::new();
-    let mut file = std::fs::File::open(path)?;
-    let mut buf = [0u8; HASH_BUFLEN];
+fn extension_of(path: &Path) -> String {
+    match path.extension() {
+        Some(ext) => format!(".{}", ext.to_string_lossy().to_lowercase()),
+        None => "(none)".to_string(),
+    }
+}
 
-    loop {
-        let read_bytes = file.read(&mut buf)?;
-        if read_bytes == 0 {
-            break;
-        }
-        hasher.update(buf);
+fn print_stats_by_ext(groups: &[DuplicateGroup]) {
+    let mut by_ext: HashMap<String, (u64, u64)> = HashMap::new();
+    for group in groups {
+        let (num_groups, reclaimable) = by_ext.entry(extension_of(&group.paths[0])).or_default();
+        *num_groups += 1;
+        *reclaimable += group.size * (group.paths.len() as u64 - 1);
     }
 
-    let mut hash = Hash::default();
-    hasher.finalize_into(&mut hash);
-    Ok(hash)
+    let mut rows: Vec<_> = by_ext.into_iter().collect();
+    rows.sort_by(|(a_ext, (_, a)), (b_ext, (_, b))| b.cmp(a).then_with(|| a_ext.cmp(b_ext)));
+
+    println!("{:<16} {:>8} {:>12}", "Extension", "Groups", "Reclaimable");
+    for (ext, (num_groups, reclaimable)) in rows {
+        println!(
+            "{:<16} {:>8} {:>12}",
+            ext,
+            num_groups,
+            format_bytes(reclaimable)
+        );
+    }
 }
 
-fn full_hash(path: &Path, full_hashes: &mut HashMap<PathBuf, Hash>) -> io::Result<Hash> {
-    use std::collections::hash_map::Entry;
-    match full_hashes.entry(path.to_path_buf()) {
-        Entry::Occupied(o) => Ok(*o.get()),
-        Entry::Vacant(v) => {
-            let hash = compute_full_hash(path)?;
-            v.insert(hash);
-            Ok(hash)
-        }
+fn print_stats_by_dir(actions: &[Action], options: &Options) {
+    let mut by_dir: HashMap<&Path, (u64, u64)> = HashMap::new();
+    for action in actions {
+        let dir = action.duplicate.parent().unwrap_or(Path::new(""));
+        let (num_files, reclaimable) = by_dir.entry(dir).or_default();
+        *num_files += 1;
+        *reclaimable += action.size;
+    }
+
+    let mut rows: Vec<_> = by_dir.into_iter().collect();
+    rows.sort_by(|(a_dir, (_, a)), (b_dir, (_, b))| b.cmp(a).then_with(|| a_dir.cmp(b_dir)));
+
+    println!("{:>12} {:>8}  Directory", "Reclaimable", "Files");
+    for (dir, (num_files, reclaimable)) in rows {
+        let dir = display_path(dir, options);
+        println!(
+            "{:>12} {:>8}  {}",
+            format_bytes(reclaimable),
+            num_files,
+            if dir.as_os_str().is_empty() {
+                Path::new(".").display()
+            } else {
+                dir.display()
+            }
+        );
     }
 }
 
-fn check_index(entry: &DirEntry, index: &mut Index) -> io::Result<Option<PathBuf>> {
-    use std::collections::btree_map::Entry;
-    let size = entry.metadata()?.len();
-    let index_entry = index.size_map.entry(size);
-    let path = entry.path();
-    match index_entry {
-        Entry::Occupied(mut o) => match o.get_mut() {
-            SizeMapEntry::One(prev_path) => {
-                let mut hash_map: MultiMap<Hash, PathBuf> = MultiMap::new();
-                let prev_hash = short_hash(prev_path)?;
-                hash_map.insert(prev_hash, prev_path.clone());
+fn print_largest_files(groups: &[DuplicateGroup], count: usize, options: &Options) {
+    let mut by_size: Vec<&DuplicateGroup> = groups.iter().collect();
+    // Stable, so files of the same size stay in the order they were found
+    by_size.sort_by_key(|group| Reverse(group.size));
+    let files = by_size
+        .into_iter()
+        .flat_map(|group| group.paths.iter().map(move |path| (group, path)))
+        .take(count);
 
-                let new_hash = short_hash(path)?;
-                if new_hash == prev_hash
-                    && full_hash(prev_path, &mut index.full_hashes)?
-                        == full_hash(path, &mut index.full_hashes)?
-                {
-                    return Ok(Some(prev_path.clone()));
+    println!("{:>12} {:>6}  File", "Size", "Copies");
+    for (group, path) in files {
+        println!(
+            "{:>12} {:>6}  {}",
+            format_bytes(group.size),
+            group.paths.len(),
+            display_path(path, options).display()
+        );
+    }
+}
+
+// Removes the directories that files were removed from, and then their
+// parents, for as long as they are empty. Directories that were empty before
+// the run are never touched, nor are the searched directories themselves.
+fn prune_empty_dirs(stats: &mut Stats, options: &Options) {
+    let mut candidates: BTreeSet<(usize, PathBuf)> = stats
+        .emptied_dirs
+        .drain(..)
+        .map(|dir| (dir.components().count(), dir))
+        .collect();
+    // Deepest first, so that parents are only looked at after their children
+    while let Some((depth, dir)) = candidates.pop_last() {
+        if options.paths.contains(&dir) || dir.as_os_str().is_empty() {
+            continue;
+        }
+        if !fs::read_dir(&dir).is_ok_and(|mut entries| entries.next().is_none()) {
+            continue;
+        }
+        match fs::remove_dir(&dir) {
+            Ok(()) => {
+                if options.verbose && options.format == Format::Text {
+                    println!("{:>SIZE_WIDTH$}  prune {:?}", "", dir);
                 }
-                hash_map.insert(new_hash, path.to_path_buf());
-                *o.get_mut() = SizeMapEntry::Multiple(hash_map);
-            }
-            SizeMapEntry::Multiple(hash_map) => {
-                let new_hash = short_hash(path)?;
-                if let Some(slice) = hash_map.get_slice(&new_hash) {
-                    for prev_path in slice {
-                        if full_hash(prev_path, &mut index.full_hashes)?
-                            == full_hash(path, &mut index.full_hashes)?
-                        {
-                            return Ok(Some(prev_path.clone()));
-                        }
-                    }
+                stats.pruned_dirs += 1;
+                if let Some(parent) = dir.parent() {
+                    candidates.insert((depth - 1, parent.to_path_buf()));
                 }
-                hash_map.insert(new_hash, path.to_path_buf());
             }
-        },
-        Entry::Vacant(v) => {
-            v.insert(SizeMapEntry::One(path.to_path_buf()));
+            Err(err) => eprintln!("Could not remove empty directory {:?}: {}", dir, err),
         }
-    };
+    }
+}
 
-    Ok(None)
+// Space on disk that each mode would free, counted in allocated blocks
+#[derive(Default)]
+struct Savings {
+    remove: u64,
+    // Hard links and reflinks free the same, unless the data is already shared
+    link: u64,
+    symlink: u64,
 }
 
-fn relative_path(base: &Path, target: &Path) -> io::Result<PathBuf> {
-    // Should not be called where path or target is symlink
-    let abs_base = base.canonicalize()?;
-    let abs_target = target.canonicalize()?;
+// Longest symlink target that file systems such as ext4 store in the inode
+// itself rather than in a data block of its own
+const INLINE_SYMLINK_LEN: usize = 60;
 
-    let mut iter_base = abs_base.components();
-    let mut iter_target = abs_target.components().peekable();
+#[derive(Default)]
+struct Stats {
+    num_actions: u64,
+    saved_bytes: u64,
+    savings: Savings,
+    skipped_groups: u64,
+    skipped_multilinked: u64,
+    skipped_readonly: u64,
+    // Groups left alone because their hash is listed in --keep-hashes
+    protected_groups: u64,
+    // Groups with fewer copies than --min-dupes-to-act
+    small_groups: u64,
+    // Duplicates only reported because the --free-target was already met,
+    // and their total size
+    beyond_free_target: u64,
+    beyond_free_target_bytes: u64,
+    // Bytes read and time taken by --verify-all
+    verified_bytes: u64,
+    verify_time: Duration,
+    // Directories that files were removed from, for --prune-empty-dirs
+    emptied_dirs: Vec<PathBuf>,
+    // Duplicates that were, or would be, replaced, for --db
+    #[cfg(feature = "sqlite")]
+    replaced: HashSet<PathBuf>,
+    pruned_dirs: u64,
+    // Duplicates left alone because a new link failed --link-check
+    failed_link_checks: u64,
+    // Duplicates left alone because they are not below --act-under
+    outside_act_under: u64,
+    // Commands for --emit-script, one per duplicate
+    script: Vec<Vec<u8>>,
+    // Group whose kept file was printed last, for --report-duplicates-only-once
+    reported_group: Option<usize>,
+}
 
-    loop {
-        let c_base = iter_base.next();
-        let c_target = iter_target.peek();
-        if c_base.is_none() || c_target.is_none() || c_base.unwrap() != *c_target.unwrap() {
-            break;
+// Whether a symlink at `link` with the target `target` would resolve to the
+// same file as `keeper`. Resolving also catches targets that loop.
+fn link_reaches(link: &Path, target: &Path, keeper: &Path) -> io::Result<bool> {
+    let resolved = fs::metadata(link.parent().unwrap_or(Path::new(".")).join(target))?;
+    let keeper = fs::metadata(keeper)?;
+    Ok((resolved.dev(), resolved.ino()) == (keeper.dev(), keeper.ino()))
+}
+
+// Sets the access time to now, leaving the modification time alone. This
+// works on file systems mounted with noatime too, as that only stops reads
+// from updating the access time.
+fn touch_accessed(path: &Path) -> io::Result<()> {
+    let file = fs::File::open(path)?;
+    file.set_times(fs::FileTimes::new().set_accessed(std::time::SystemTime::now()))
+}
+
+// Compares the keeper with every duplicate of its group byte by byte
+fn verify_group(action: &Action, scan: &Scan, options: &Options) -> io::Result<bool> {
+    let len = action.size.saturating_sub(options.ignore_trailing);
+    for other in scan
+        .actions
+        .iter()
+        .filter(|other| other.group == action.group)
+    {
+        if !contents_equal(&action.keeper, &other.duplicate, len)? {
+            return Ok(false);
         }
-        iter_target.next();
     }
-
-    let relative = iter_base
-        .map(|a| match a {
-            std::path::Component::Normal(_) => std::path::Component::ParentDir,
-            _ => panic!(),
-        })
-        .chain(iter_target)
-        .collect::<PathBuf>();
-    Ok(relative)
+    Ok(true)
 }
 
-fn format_bytes(num: u64) -> String {
-    match NumberPrefix::binary(num as f64) {
-        NumberPrefix::Standalone(bytes) => {
-            format!("{} bytes", bytes)
+// Verifies every group that has duplicates and is not protected by
+// --keep-hashes, in parallel with --parallel-verify. Returns what is wrong
+// with each group that fails.
+fn verify_groups(
+    scan: &Scan,
+    options: &Options,
+    keep_hashes: &HashSet<Hash>,
+    stats: &mut Stats,
+) -> HashMap<usize, String> {
+    let mut first_actions: BTreeMap<usize, &Action> = BTreeMap::new();
+    for action in &scan.actions {
+        if !keep_hashes.contains(&scan.groups[action.group].hash) {
+            first_actions.entry(action.group).or_insert(action);
         }
-        NumberPrefix::Prefixed(prefix, n) => {
-            format!("{:.1} {}B", n, prefix)
+    }
+    let verify = |action: &Action| {
+        let problem = match verify_group(action, scan, options) {
+            Ok(true) => return None,
+            Ok(false) => "not all copies are identical".to_string(),
+            Err(err) => format!("cannot compare the copies: {}", err),
+        };
+        Some((action.group, problem))
+    };
+
+    let start = Instant::now();
+    // Each comparison streams through both files with a small buffer, so
+    // memory use is bounded by the number of threads
+    let problems = if options.parallel_verify {
+        let actions: Vec<&Action> = first_actions.into_values().collect();
+        actions.into_par_iter().filter_map(verify).collect()
+    } else {
+        first_actions.into_values().filter_map(verify).collect()
+    };
+    stats.verify_time = start.elapsed();
+    // Both files of each comparison are read, unless they differ early on
+    stats.verified_bytes = scan
+        .actions
+        .iter()
+        .map(|action| 2 * action.size.saturating_sub(options.ignore_trailing))
+        .sum();
+    problems
+}
+
+// Resolves the directories leading to `path`, but not `path` itself, which
+// may be a symlink
+fn resolved_location(path: &Path) -> Option<PathBuf> {
+    let parent = match path.parent()? {
+        dir if dir.as_os_str().is_empty() => Path::new("."),
+        dir => dir,
+    };
+    Some(parent.canonicalize().ok()?.join(path.file_name()?))
+}
+
+// Says why the duplicate of `action` must never be removed or replaced, if
+// so. The action loop already leaves such files alone; this is checked again
+// right before acting, to catch mistakes in that logic.
+fn protection(
+    action: &Action,
+    group: &DuplicateGroup,
+    options: &Options,
+    keep_hashes: &HashSet<Hash>,
+) -> Option<&'static str> {
+    let duplicate = resolved_location(&action.duplicate);
+    if duplicate.is_some() && duplicate == resolved_location(&action.keeper) {
+        return Some("is the kept copy");
+    }
+    if keep_hashes.contains(&group.hash) {
+        return Some("has a hash listed in --keep-hashes");
+    }
+    if !options.writable.is_empty()
+        && !options
+            .writable
+            .iter()
+            .any(|dir| is_below(&action.duplicate, dir))
+    {
+        return Some("is outside the --writable directories");
+    }
+    if let Some(dir) = &options.act_under {
+        if !is_below(&action.duplicate, dir) {
+            return Some("is outside the --act-under directory");
         }
     }
+    None
 }
 
-fn handle_entry(
-    entry: &DirEntry,
+fn handle_action(
+    action: &Action,
+    scan: &Scan,
     options: &Options,
-    index: &mut Index,
+    keep_hashes: &HashSet<Hash>,
     stats: &mut Stats,
-) -> anyhow::Result<()> {
-    let size = entry.metadata()?.len();
-    if entry.file_type().is_file() && size > options.min_size {
-        if let Some(prev_path) = check_index(entry, index)? {
-            if prev_path != entry.path() {
-                let rel = relative_path(entry.path(), &prev_path)?;
-                if options.remove || options.replace_by_symlink {
-                    fs::remove_file(entry.path())?;
-                    if options.replace_by_symlink {
-                        std::os::unix::fs::symlink(&rel, entry.path())?;
-                    }
+    skipped_group: &mut Option<usize>,
+) -> io::Result<()> {
+    if !options.writable.is_empty() {
+        let duplicate = action.duplicate.canonicalize()?;
+        if !options
+            .writable
+            .iter()
+            .any(|dir| duplicate.starts_with(dir))
+        {
+            if options.remove || options.replace_by_symlink {
+                eprintln!(
+                    "Warning: leaving {:?} alone, it is outside the --writable directories",
+                    action.duplicate
+                );
+            }
+            stats.skipped_readonly += 1;
+            return Ok(());
+        }
+    }
+    if let Some(dir) = &options.act_under {
+        if !is_below(&action.duplicate, dir) {
+            stats.outside_act_under += 1;
+            return Ok(());
+        }
+    }
+    let rel = relative_path(&action.duplicate, &action.keeper)?;
+    let metadata = fs::symlink_metadata(&action.duplicate)?;
+    // Replacing a symlink frees no space
+    let mut saved_bytes = if metadata.is_symlink() {
+        0
+    } else {
+        action.size
+    };
+    let nlink = metadata.nlink();
+    if nlink > 1 {
+        if options.skip_multilinked {
+            if options.verbose {
+                println!(
+                    "{:>SIZE_WIDTH$}  skip {:?}, it has {} other hard links",
+                    format_bytes(action.size),
+                    display_path(&action.duplicate, options),
+                    nlink - 1
+                );
+            }
+            stats.skipped_multilinked += 1;
+            return Ok(());
+        }
+        if options.remove || options.replace_by_symlink {
+            eprintln!(
+                "Warning: {:?} has {} other hard links, removing it frees no space",
+                action.duplicate,
+                nlink - 1
+            );
+        }
+        saved_bytes = 0;
+    }
+    let freed = if saved_bytes > 0 {
+        metadata.blocks() * 512
+    } else {
+        0
+    };
+    let symlink_cost = if rel.as_os_str().len() > INLINE_SYMLINK_LEN {
+        metadata.blksize()
+    } else {
+        0
+    };
+    if options.remove || options.replace_by_symlink {
+        if let Err(err) = check_readable(&action.keeper, action.size) {
+            eprintln!(
+                "Keeping all remaining copies of {:?}: {}",
+                action.keeper, err
+            );
+            *skipped_group = Some(action.group);
+            stats.skipped_groups += 1;
+            return Ok(());
+        }
+        if let Some(reason) = protection(action, &scan.groups[action.group], options, keep_hashes) {
+            panic!(
+                "Internal error: about to change {:?}, which {}. Stopped before changing it; please report this.",
+                action.duplicate, reason
+            );
+        }
+        if options.replace_by_symlink && options.link_check {
+            match link_reaches(&action.duplicate, &rel, &action.keeper) {
+                Ok(true) => {}
+                Ok(false) => {
+                    eprintln!(
+                        "Warning: leaving {:?} alone, a link to {:?} would lead elsewhere",
+                        action.duplicate, rel
+                    );
+                    stats.failed_link_checks += 1;
+                    return Ok(());
                 }
-                if options.verbose {
-                    if options.remove {
-                        println!("({}) remove {:?}", format_bytes(size), entry.path());
-                    } else {
-                        println!(
-                            "({}) link {:?} -> {:?}",
-                            format_bytes(size),
-                            entry.path(),
-                            rel
-                        );
-                    }
+                Err(err) => {
+                    eprintln!(
+                        "Warning: leaving {:?} alone, a link to {:?} would not resolve: {}",
+                        action.duplicate, rel, err
+                    );
+                    stats.failed_link_checks += 1;
+                    return Ok(());
                 }
-                stats.saved_bytes += size;
-                stats.num_actions += 1;
             }
         }
-        stats.num_files += 1;
+        if options.emit_script.is_some() {
+            let duplicate = shell_quote(action.duplicate.as_os_str());
+            let mut command = b"rm -- ".to_vec();
+            command.extend_from_slice(&duplicate);
+            if options.replace_by_symlink {
+                command.extend_from_slice(b" && ln -s -- ");
+                command.extend_from_slice(&shell_quote(rel.as_os_str()));
+                command.push(b' ');
+                command.extend_from_slice(&duplicate);
+            }
+            stats.script.push(command);
+        } else if options.dry_run {
+            if options.format == Format::Text {
+                let duplicate = display_path(&action.duplicate, options);
+                let size = format_bytes(action.size);
+                if options.replace_by_symlink {
+                    println!(
+                        "{:>SIZE_WIDTH$}  would link {:?} -> {:?}",
+                        size, duplicate, rel
+                    );
+                } else {
+                    println!("{:>SIZE_WIDTH$}  would remove {:?}", size, duplicate);
+                }
+            }
+        } else {
+            tracing::info!(
+                duplicate = ?action.duplicate,
+                keeper = ?action.keeper,
+                symlink = options.replace_by_symlink,
+                "taking action"
+            );
+            fs::remove_file(&action.duplicate)?;
+            if options.replace_by_symlink {
+                std::os::unix::fs::symlink(&rel, &action.duplicate)?;
+            } else if options.prune_empty_dirs {
+                if let Some(parent) = action.duplicate.parent() {
+                    stats.emptied_dirs.push(parent.to_path_buf());
+                }
+            }
+        }
+    }
+    if options.verbose && !options.dry_run && options.format == Format::Text {
+        let duplicate = paint(
+            format!("{:?}", display_path(&action.duplicate, options)),
+            RED,
+            options,
+        );
+        let size = format_bytes(action.size);
+        if options.remove {
+            println!("{:>SIZE_WIDTH$}  remove {}", size, duplicate);
+        } else if options.replace_by_symlink {
+            println!("{:>SIZE_WIDTH$}  link {} -> {:?}", size, duplicate, rel);
+        } else if options.report_duplicates_only_once {
+            if stats.reported_group != Some(action.group) {
+                let group = &scan.groups[action.group];
+                println!(
+                    "{:>SIZE_WIDTH$}  keep {} ({} copies, hash {}), remove:",
+                    size,
+                    paint(
+                        format!("{:?}", display_path(&action.keeper, options)),
+                        GREEN,
+                        options
+                    ),
+                    group.paths.len(),
+                    format_hash(&group.hash, options)
+                );
+                stats.reported_group = Some(action.group);
+            }
+            println!("{:SIZE_WIDTH$}    {}", "", duplicate);
+        } else {
+            println!(
+                "{:>SIZE_WIDTH$}  would remove {} (dup of {}, hash {})",
+                size,
+                duplicate,
+                paint(
+                    format!("{:?}", display_path(&action.keeper, options)),
+                    GREEN,
+                    options
+                ),
+                format_hash(&scan.groups[action.group].hash, options)
+            );
+        }
+    }
+    if let Some(template) = &options.exec {
+        let command = exec_command(template, action, &scan.groups[action.group]);
+        if options.emit_script.is_some() {
+            stats.script.push(command);
+        } else if options.dry_run {
+            println!("would run {}", String::from_utf8_lossy(&command));
+        } else {
+            run_command(&command)?;
+        }
     }
+    stats.saved_bytes += saved_bytes;
+    stats.savings.remove += freed;
+    stats.savings.link += freed;
+    stats.savings.symlink += freed.saturating_sub(symlink_cost);
+    stats.num_actions += 1;
     Ok(())
 }
 
-struct Stats {
-    num_files: u64,
-    num_actions: u64,
-    saved_bytes: u64,
-}
-
 fn main() -> anyhow::Result<()> {
-    let options = Options::parse();
-
-    let mut index = Index {
-        size_map: BTreeMap::new(),
-        full_hashes: HashMap::new(),
+    let start = Instant::now();
+    let matches = Options::command().get_matches();
+    let mut options = Options::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    init_logging(options.log_level.as_deref())?;
+    match &options.command {
+        Some(Command::Selftest) => {
+            if !selftest::run()? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Diff {
+            verbose,
+            left,
+            right,
+        }) => return report_diff(left, right, *verbose),
+        Some(Command::Stats { paths }) => return report_stats(paths),
+        Some(Command::Bench { paths }) => return run_bench(paths),
+        Some(Command::Compare { left, right }) => {
+            if !run_compare(left, right)? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+    if let Some(config) = Config::load(options.config.as_deref())? {
+        config.apply(&mut options, &matches);
+    }
+    if let Some(base) = &mut options.relative_to {
+        *base = std::path::absolute(&*base)
+            .with_context(|| format!("Invalid --relative-to directory {:?}", base))?;
+    }
+    if options.deterministic {
+        options.progress = false;
+        options.adaptive_buffer = false;
+        options.color = ColorChoice::Never;
+    }
+    options.hash_cache = match options.hash_cache_arg.clone() {
+        Some(Some(path)) => Some(path),
+        Some(None) => {
+            let path = config::default_hash_cache()
+                .context("Cannot find the user's cache directory for --hash-cache")?;
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create the cache directory {:?}", dir))?;
+            }
+            Some(path)
+        }
+        None => None,
     };
+    options.use_color = match options.color {
+        ColorChoice::Auto => io::stdout().is_terminal(),
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+    if let Some(library) = &mut options.library {
+        *library = library
+            .canonicalize()
+            .with_context(|| format!("Invalid --library directory {:?}", library))?;
+        options.replace_by_symlink = true;
+        options.remove = false;
+    }
+    if options.emit_script.is_some() {
+        if !options.remove && !options.replace_by_symlink {
+            anyhow::bail!("--emit-script needs --symlink or --remove");
+        }
+        // The script is run later, if at all
+        options.dry_run = true;
+    }
+    if options.yes && options.on_error == ErrorPolicy::Prompt {
+        // "Skip this file and continue?"
+        options.on_error = ErrorPolicy::Skip;
+    }
+    if let Some(dir) = &mut options.act_under {
+        *dir = dir
+            .canonicalize()
+            .with_context(|| format!("Invalid --act-under directory {:?}", dir))?;
+    }
+    for dir in &mut options.writable {
+        *dir = dir
+            .canonicalize()
+            .with_context(|| format!("Invalid --writable directory {:?}", dir))?;
+    }
+    let keep_hashes = match &options.keep_hashes {
+        Some(path) => read_hash_list(path)
+            .with_context(|| format!("Failed to read --keep-hashes file {:?}", path))?,
+        None => HashSet::new(),
+    };
+    if options.dirs {
+        return report_duplicate_dirs(&options);
+    }
+    if options.fast {
+        if (options.remove || options.replace_by_symlink || options.exec.is_some())
+            && !options.i_know_what_im_doing
+        {
+            anyhow::bail!("--fast only compares part of each file, so it is report-only; add --i-know-what-im-doing to change files or run --exec anyway");
+        }
+        eprintln!("Warning: --fast only compares the first and last 64 KiB of each file. Reported duplicates are not verified and may differ in between.");
+    }
+    #[cfg(feature = "archive")]
+    if options.archive {
+        return report_archive_duplicates(&options);
+    }
+    if options.dedup_symlinks {
+        return report_duplicate_symlinks(&options);
+    }
+    if options.size_only {
+        return report_same_size_files(&options);
+    }
+    #[cfg(feature = "decompress")]
+    if options.compressed_copies {
+        return report_compressed_copies(&options);
+    }
+    #[cfg(feature = "fuzzy")]
+    if options.similar {
+        return report_similar_files(&options);
+    }
+    #[cfg(feature = "cdc")]
+    if options.cdc_estimate {
+        return report_chunk_estimate(&options);
+    }
+    if options.estimate {
+        return report_estimate(&options);
+    }
+    #[cfg(feature = "remote")]
+    if let Some(url) = &options.reference_url {
+        return report_reference_matches(url, &options);
+    }
+    if let Some(path) = &options.hash_manifest {
+        return write_manifest(path, ManifestFormat::Plain, &options);
+    }
+    if let Some(path) = &options.checksum_file {
+        let format = if options.checksum_binary {
+            ManifestFormat::Sha256sumBinary
+        } else {
+            ManifestFormat::Sha256sum
+        };
+        return write_manifest(path, format, &options);
+    }
 
-    let mut stats = Stats {
-        num_files: 0,
-        num_actions: 0,
-        saved_bytes: 0,
+    let cancel = Arc::new(AtomicBool::new(false));
+    let handler_cancel = cancel.clone();
+    ctrlc::set_handler(move || handler_cancel.store(true, Ordering::Relaxed))?;
+
+    // A checkpoint left behind by an interrupted run is read like a manifest
+    let resumed = options.checkpoint.as_ref().filter(|path| path.exists());
+    let cached = options.hash_cache.as_ref().filter(|path| path.exists());
+    let mut scan_options = ScanOptions {
+        adaptive_buffer: options.adaptive_buffer,
+        same_ext: options.same_ext,
+        ignore_trailing: options.ignore_trailing,
+        max_files: options.max_files,
+        fast: options.fast,
+        progress: options.progress,
+        two_pass: options.two_pass,
+        no_short_hash: options.no_short_hash,
+        cancel: Some(cancel.clone()),
+        checkpoint: options.checkpoint.clone().or(options.hash_cache.clone()),
+        normalize: normalizer(&options),
+        include_forks: options.include_forks,
+        #[cfg(feature = "decompress")]
+        decompress: options.decompress,
+        ..filter_options(&options)
     };
+    if let Some(path) = options.since.as_ref().or(resumed).or(cached) {
+        let manifest = PriorManifest::read(path)
+            .with_context(|| format!("Failed to read the manifest {:?}", path))?;
+        if manifest.matches(&scan_options) {
+            if resumed.is_some() {
+                eprintln!("Resuming from the checkpoint {:?}.", path);
+            }
+            scan_options.since = Some(Arc::new(manifest));
+        } else if options.since.is_some() {
+            anyhow::bail!(
+                "The manifest {:?} was not written with the same --ignore-trailing and hash function",
+                path
+            );
+        } else if resumed.is_some() {
+            eprintln!(
+                "Not resuming from the checkpoint {:?}: it was written with another --ignore-trailing or hash function.",
+                path
+            );
+        } else {
+            eprintln!(
+                "Not using the hashes in {:?}: they were computed with another --ignore-trailing or hash function. The file will be replaced.",
+                path
+            );
+        }
+    }
+    let mut scan = find_duplicates(&options.paths, &scan_options, |group| {
+        choose_keeper(group, &options)
+    })?;
+
+    if options.deterministic {
+        sort_groups(&mut scan);
+    }
+    let mut stats = Stats::default();
 
-    for dir in &options.paths {
-        let mut walk = WalkDir::new(dir);
-        if let Some(max_depth) = options.max_depth {
-            walk = walk.max_depth(max_depth);
+    if scan.cancelled {
+        eprintln!("Interrupted, results are incomplete.");
+        if options.remove || options.replace_by_symlink {
+            eprintln!("No files were changed.");
+            std::process::exit(130);
         }
-        for _entry in walk {
-            match &_entry {
-                Ok(entry) => handle_entry(entry, &options, &mut index, &mut stats)?,
-                Err(err) => eprintln!("{}", err),
+    }
+
+    if scan.reached_max_files {
+        eprintln!(
+            "Stopped after {} files because of --max-files.",
+            scan.num_files
+        );
+    }
+
+    if let Some(limit) = options.confirm_over {
+        let total: u64 = scan.actions.iter().map(|action| action.size).sum();
+        let changes_files = (options.remove || options.replace_by_symlink) && !options.dry_run;
+        if changes_files && total > limit && !options.yes {
+            let question = format!(
+                "About to replace {} duplicates, {} in total. Continue?",
+                scan.actions.len(),
+                format_bytes(total)
+            );
+            if !io::stdin().is_terminal() {
+                anyhow::bail!(
+                    "{} is more than --confirm-over allows; pass --yes to proceed without a terminal",
+                    format_bytes(total)
+                );
+            }
+            if !confirm(&question) {
+                eprintln!("No files were changed.");
+                std::process::exit(1);
             }
         }
     }
 
-    print!("Processed {} files. ", stats.num_files);
-    if options.remove || options.replace_by_symlink {
-        if options.remove {
-            print!("Removed {} files", stats.num_actions);
-        } else {
-            /* if options.replace_by_symlink  */
-            print!("Created {} symlinks", stats.num_actions);
+    let mut action_errors = Vec::new();
+    if let Some(library) = &options.library {
+        if !scan.cancelled {
+            consolidate(
+                &mut scan,
+                library,
+                &options,
+                |group| {
+                    keep_hashes.contains(&group.hash)
+                        || (group.paths.len() as u64) < options.min_dupes_to_act
+                },
+                &mut action_errors,
+            )?;
         }
-        println!(", saving {}.", format_bytes(stats.saved_bytes));
+    }
+    if options.free_target.is_some() {
+        // The sort is stable, so the actions of a group stay together
+        scan.actions.sort_by_key(|action| Reverse(action.size));
+    }
+    let _span = tracing::info_span!("actions", count = scan.actions.len()).entered();
+    let verify_problems = if options.verify_all {
+        verify_groups(&scan, &options, &keep_hashes, &mut stats)
     } else {
-        println!(
-            "Found {} duplicates. Removing them would save {}.",
-            stats.num_actions,
-            format_bytes(stats.saved_bytes)
+        HashMap::new()
+    };
+    if let Some(path) = &options.plan_json {
+        build_plan(&scan, &options, &keep_hashes)
+            .write(path)
+            .with_context(|| format!("Failed to write the plan {:?}", path))?;
+    }
+    let mut skipped_group = None;
+    let mut touched_keepers = BTreeSet::new();
+    for action in &scan.actions {
+        if (options.remove || options.replace_by_symlink) && cancel.load(Ordering::Relaxed) {
+            eprintln!("Interrupted, stopping before {:?}.", action.duplicate);
+            break;
+        }
+        if skipped_group == Some(action.group) {
+            continue;
+        }
+        if keep_hashes.contains(&scan.groups[action.group].hash) {
+            if options.verbose {
+                println!(
+                    "{:>SIZE_WIDTH$}  keep all copies of {:?}, its hash is listed in --keep-hashes",
+                    format_bytes(action.size),
+                    display_path(&action.keeper, &options)
+                );
+            }
+            stats.protected_groups += 1;
+            skipped_group = Some(action.group);
+            continue;
+        }
+        let copies = scan.groups[action.group].paths.len() as u64;
+        if (options.remove || options.replace_by_symlink) && copies < options.min_dupes_to_act {
+            if options.verbose && options.format == Format::Text {
+                println!(
+                    "{:>SIZE_WIDTH$}  leave {:?} alone, it has only {} copies",
+                    format_bytes(action.size),
+                    display_path(&action.keeper, &options),
+                    copies
+                );
+            }
+            stats.small_groups += 1;
+            skipped_group = Some(action.group);
+            continue;
+        }
+        if let Some(problem) = verify_problems.get(&action.group) {
+            eprintln!(
+                "Warning: leaving the copies of {:?} alone, {}",
+                action.keeper, problem
+            );
+            skipped_group = Some(action.group);
+            stats.skipped_groups += 1;
+            continue;
+        }
+        if let Some(max_actions) = options.max_actions.filter(|&max| stats.num_actions >= max) {
+            eprintln!(
+                "Stopped after {} duplicates because of --max-actions.",
+                max_actions
+            );
+            break;
+        }
+        if (options.remove || options.replace_by_symlink)
+            && options
+                .free_target
+                .is_some_and(|target| stats.saved_bytes >= target)
+        {
+            if options.verbose && options.format == Format::Text {
+                println!(
+                    "{:>SIZE_WIDTH$}  leave {:?} alone, the --free-target is met",
+                    format_bytes(action.size),
+                    display_path(&action.duplicate, &options)
+                );
+            }
+            stats.beyond_free_target += 1;
+            stats.beyond_free_target_bytes += action.size;
+            continue;
+        }
+        let num_actions = stats.num_actions;
+        match handle_action(
+            action,
+            &scan,
+            &options,
+            &keep_hashes,
+            &mut stats,
+            &mut skipped_group,
+        ) {
+            Ok(()) if stats.num_actions > num_actions => {
+                #[cfg(feature = "sqlite")]
+                if options.db.is_some() {
+                    stats.replaced.insert(action.duplicate.clone());
+                }
+                if options.touch_keeper
+                    && (options.remove || options.replace_by_symlink)
+                    && !options.dry_run
+                {
+                    touched_keepers.insert(&action.keeper);
+                }
+            }
+            Ok(()) => {}
+            Err(err) if is_name_too_long(&err) => {
+                eprintln!("Skipping {:?}: {}", action.duplicate, err);
+                action_errors.push(SkippedFile::new(&action.duplicate, &err));
+            }
+            Err(err) => {
+                let skipped = SkippedFile::new(&action.duplicate, &err);
+                options.on_error.handle(&action.duplicate, err)?;
+                action_errors.push(skipped);
+            }
+        }
+    }
+    for keeper in touched_keepers {
+        if let Err(err) = touch_accessed(keeper) {
+            eprintln!("Could not set the access time of {:?}: {}", keeper, err);
+        }
+    }
+    if let Some(target) = options.free_target {
+        if stats.saved_bytes >= target {
+            eprintln!("The --free-target of {} was met.", format_bytes(target));
+        } else {
+            eprintln!(
+                "The --free-target of {} was not met, the duplicates add up to {}.",
+                format_bytes(target),
+                format_bytes(stats.saved_bytes)
+            );
+        }
+    }
+    if let Some(path) = &options.emit_script {
+        write_script(path, &stats)
+            .with_context(|| format!("Failed to write the script {:?}", path))?;
+        eprintln!(
+            "Wrote the commands for {} duplicates to {:?}.",
+            stats.num_actions, path
+        );
+    }
+    let errors: Vec<&SkippedFile> = scan.errors.iter().chain(&action_errors).collect();
+
+    if options.stats {
+        eprintln!(
+            "Read buffer size: {}",
+            format_bytes(scan.buffer_size as u64)
+        );
+        eprintln!("Files read for a full hash: {}", scan.full_hash_reads);
+        if options.since.is_some() {
+            eprintln!(
+                "Full hashes taken from the --since manifest: {}",
+                scan.reused_hashes
+            );
+        }
+        eprintln!(
+            "Peak index memory (estimate): {}",
+            format_bytes(scan.peak_index_bytes as u64)
         );
+        if options.deterministic {
+            eprintln!("Read for hashing: {}", format_bytes(scan.bytes_read));
+        } else {
+            let secs = scan.scan_time.as_secs_f64().max(1e-3);
+            eprintln!(
+                "Read for hashing: {} in {:.1?} ({}/s)",
+                format_bytes(scan.bytes_read),
+                scan.scan_time,
+                format_bytes((scan.bytes_read as f64 / secs) as u64)
+            );
+            eprintln!(
+                "Files scanned per second: {:.0}",
+                scan.num_files as f64 / secs
+            );
+        }
+        if options.verify_all && options.deterministic {
+            eprintln!("Verified {}", format_bytes(stats.verified_bytes));
+        } else if options.verify_all {
+            eprintln!(
+                "Verified {} in {:.1?} ({}/s)",
+                format_bytes(stats.verified_bytes),
+                stats.verify_time,
+                format_bytes(
+                    (stats.verified_bytes as f64 / stats.verify_time.as_secs_f64().max(1e-3))
+                        as u64
+                )
+            );
+        }
+    }
+
+    if options.prune_empty_dirs {
+        prune_empty_dirs(&mut stats, &options);
+    }
+
+    match options.format {
+        Format::Text if options.terse => println!(
+            "{}\t{}\t{}",
+            scan.num_files, stats.num_actions, stats.saved_bytes
+        ),
+        Format::Text => {
+            print_summary(&scan, &stats, &options);
+            print_error_summary(&errors, &options);
+            if options.stats_by_ext {
+                print_stats_by_ext(&scan.groups);
+            }
+            if options.by_dir {
+                print_stats_by_dir(&scan.actions, &options);
+            }
+            if let Some(count) = options.largest_files {
+                print_largest_files(&scan.groups, count, &options);
+            }
+        }
+        Format::Json => build_report(&scan, &stats, &errors, &options).print()?,
+        Format::Xml => xml::print(&build_report(&scan, &stats, &errors, &options))?,
+        Format::Dot => print_dot(&scan.groups, &options)?,
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &options.db {
+        write_db(path, &scan, &stats, &options)
+            .with_context(|| format!("Failed to write the database {:?}", path))?;
+    }
+    if let Some(path) = &options.append_results {
+        append_results(path, &scan.groups)
+            .with_context(|| format!("Failed to add the results to {:?}", path))?;
+    }
+    if let Some(path) = &options.metrics_file {
+        let metrics = Metrics {
+            files_processed: scan.num_files,
+            bytes_reclaimable: stats.saved_bytes,
+            actions_total: stats.num_actions,
+            run_duration: if options.deterministic {
+                Duration::ZERO
+            } else {
+                start.elapsed()
+            },
+        };
+        metrics
+            .write(path)
+            .with_context(|| format!("Failed to write metrics to {:?}", path))?;
+    }
+    // Only an interrupted run leaves its checkpoint and lock file behind
+    if let Some(path) = options.checkpoint.as_ref().filter(|_| !scan.cancelled) {
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        for path in [path.clone(), PathBuf::from(lock_path)] {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => eprintln!("Could not remove the checkpoint {:?}: {}", path, err),
+            }
+        }
     }
     anyhow::Ok(())
 }
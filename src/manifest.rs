@@ -0,0 +1,447 @@
+use crate::filter::Filter;
+use crate::{distinct_roots, from_hex, to_hex, walk_root, Contents, Hash, HashCache, ScanOptions};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// How often `Checkpoint` writes the hashes computed since the last write
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+// Input whose hash identifies the hash function in `settings_line`
+const HASHER_PROBE: &[u8] = b"dedup";
+
+/// Line format of a hash manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    /// `<hash> <size> <mtime> <path>`, with the modification time as
+    /// `<seconds>.<nanoseconds>` since the epoch, after a first line starting
+    /// with `#` that records the settings the hashes were computed with.
+    /// Backslashes and line breaks in paths are escaped, as are the bytes
+    /// outside ASCII of paths that are not valid UTF-8 (as `\xHH`).
+    Plain,
+    /// `<hash>  <path>`, as written by GNU `sha256sum` in text mode
+    Sha256sum,
+    /// `<hash> *<path>`, as written by GNU `sha256sum` in binary mode
+    Sha256sumBinary,
+}
+
+// First line of a manifest in the `ManifestFormat::Plain` format. Hashes
+// depend on `ignore_trailing` and the hash function, so a manifest is only
+// used by scans with the same ones; see `PriorManifest::matches`.
+fn settings_line(options: &ScanOptions) -> String {
+    format!(
+        "# dedup ignore-trailing={} hasher={}",
+        options.ignore_trailing,
+        to_hex(&options.hasher.hash_bytes(HASHER_PROBE))
+    )
+}
+
+// Writes a line in the format of `sha256sum`. Like coreutils, backslashes and
+// newlines in the name are escaped, and such lines start with a backslash.
+fn write_checksum_line(
+    out: &mut impl Write,
+    hash: &str,
+    path: &Path,
+    binary: bool,
+) -> io::Result<()> {
+    let name = path.as_os_str().as_bytes();
+    let escape = name.iter().any(|&b| b == b'\\' || b == b'\n');
+    if escape {
+        out.write_all(b"\\")?;
+    }
+    write!(out, "{} {}", hash, if binary { '*' } else { ' ' })?;
+    for &b in name {
+        match b {
+            b'\\' if escape => out.write_all(b"\\\\")?,
+            b'\n' => out.write_all(b"\\n")?,
+            _ => out.write_all(&[b])?,
+        }
+    }
+    out.write_all(b"\n")
+}
+
+// Writes a line in the `ManifestFormat::Plain` format
+fn write_plain_line(
+    out: &mut impl Write,
+    hash: &str,
+    size: u64,
+    (secs, nanos): (i64, i64),
+    path: &Path,
+) -> io::Result<()> {
+    write!(out, "{} {} {}.{:09} ", hash, size, secs, nanos)?;
+    out.write_all(&escape_path(path))?;
+    out.write_all(b"\n")
+}
+
+// Escapes backslashes and line breaks in `path`, and, if it is not valid
+// UTF-8, all other bytes outside ASCII as `\xHH`, so that the path fits on
+// one line of a UTF-8 text file and `unescape_path` gives back its exact
+// bytes
+fn escape_path(path: &Path) -> Vec<u8> {
+    let utf8 = path.to_str().is_some();
+    let mut escaped = Vec::new();
+    for &b in path.as_os_str().as_bytes() {
+        match b {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            0x80.. if !utf8 => escaped.extend_from_slice(format!("\\x{:02x}", b).as_bytes()),
+            _ => escaped.push(b),
+        }
+    }
+    escaped
+}
+
+fn unescape_path(escaped: &str) -> Option<PathBuf> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+        let (&kind, tail) = rest.split_first()?;
+        rest = tail;
+        match kind {
+            b'\\' => bytes.push(b'\\'),
+            b'n' => bytes.push(b'\n'),
+            b'r' => bytes.push(b'\r'),
+            b'x' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            _ => return None,
+        }
+    }
+    Some(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+/// Walks `paths` and writes a line with the hash of every file to `out`, in
+/// the given format, without looking for duplicates. Returns the number of
+/// files written.
+pub fn write_hash_manifest(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+    format: ManifestFormat,
+    mut out: impl Write,
+) -> io::Result<u64> {
+    let filter = Filter::new(options)?;
+    let mut hashes = HashCache::new(options, Contents::Raw);
+    let mut num_files = 0;
+    if format == ManifestFormat::Plain {
+        writeln!(out, "{}", settings_line(options))?;
+    }
+
+    for root in &distinct_roots(paths) {
+        for entry in walk_root(root, options, &filter, &mut 0)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() || !filter.has_wanted_extension(entry.path()) {
+                continue;
+            }
+            let size = match entry.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(err) => {
+                    options.on_error.handle(entry.path(), err.into())?;
+                    continue;
+                }
+            };
+            if size <= filter.min_size(entry.path()) {
+                continue;
+            }
+            // Not cached: every file is hashed exactly once
+            let hash = match hashes.hash_uncached(entry.path(), size) {
+                Ok(hash) => to_hex(&hash),
+                Err(err) => {
+                    options.on_error.handle(entry.path(), err)?;
+                    continue;
+                }
+            };
+            match format {
+                ManifestFormat::Plain => {
+                    let (secs, nanos) = match entry.metadata() {
+                        Ok(metadata) => (metadata.mtime(), metadata.mtime_nsec()),
+                        Err(err) => {
+                            options.on_error.handle(entry.path(), err.into())?;
+                            continue;
+                        }
+                    };
+                    write_plain_line(&mut out, &hash, size, (secs, nanos), entry.path())?
+                }
+                ManifestFormat::Sha256sum => {
+                    write_checksum_line(&mut out, &hash, entry.path(), false)?
+                }
+                ManifestFormat::Sha256sumBinary => {
+                    write_checksum_line(&mut out, &hash, entry.path(), true)?
+                }
+            }
+            num_files += 1;
+        }
+    }
+
+    out.flush()?;
+    Ok(num_files)
+}
+
+/// Hashes recorded in a manifest written by an earlier run in the
+/// `ManifestFormat::Plain` format, by path.
+#[derive(Debug, Default)]
+pub struct PriorManifest {
+    entries: HashMap<PathBuf, (u64, (i64, i64), Hash)>,
+    // The first line, if it records settings; see `settings_line`
+    settings: Option<String>,
+}
+
+impl PriorManifest {
+    pub fn read(path: &Path) -> io::Result<PriorManifest> {
+        let invalid = |number: usize| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {} is not in the hash manifest format", number + 1),
+            )
+        };
+        let mut entries = HashMap::new();
+        let mut settings = None;
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            if line.starts_with('#') {
+                if number == 0 {
+                    settings = Some(line.to_string());
+                }
+                continue;
+            }
+            let mut fields = line.splitn(4, ' ');
+            let (Some(hash), Some(size), Some(mtime), Some(path)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid(number));
+            };
+            let (secs, nanos) = mtime.split_once('.').ok_or_else(|| invalid(number))?;
+            let entry = (|| {
+                Some((
+                    size.parse().ok()?,
+                    (secs.parse().ok()?, nanos.parse().ok()?),
+                    from_hex(hash)?,
+                ))
+            })()
+            .ok_or_else(|| invalid(number))?;
+            entries.insert(unescape_path(path).ok_or_else(|| invalid(number))?, entry);
+        }
+        Ok(PriorManifest { entries, settings })
+    }
+
+    /// Whether the hashes in the manifest were computed with the
+    /// `ignore_trailing` and hasher of `options`. Scans only take hashes
+    /// from a manifest that matches. Manifests without a settings line
+    /// never match.
+    pub fn matches(&self, options: &ScanOptions) -> bool {
+        self.settings.as_deref() == Some(settings_line(options).as_str())
+    }
+
+    // Returns the recorded hash of the file at `path` if it still has the
+    // recorded size and modification time
+    pub(crate) fn hash_of(&self, path: &Path, size: u64) -> Option<Hash> {
+        let (recorded_size, mtime, hash) = self.entries.get(path)?;
+        if *recorded_size != size {
+            return None;
+        }
+        let metadata = fs::metadata(path).ok()?;
+        (metadata.len() == size && (metadata.mtime(), metadata.mtime_nsec()) == *mtime)
+            .then_some(*hash)
+    }
+}
+
+// Full hashes computed during a scan, written to a file from time to time in
+// the `ManifestFormat::Plain` format, so that an interrupted scan can take
+// them from it as a `PriorManifest`. Lines already in the file are kept, so
+// several processes can share one file; see `write`.
+pub(crate) struct Checkpoint {
+    path: PathBuf,
+    settings: String,
+    // Lines not written yet
+    pending: Vec<u8>,
+    last_write: Instant,
+}
+
+impl Checkpoint {
+    pub(crate) fn new(path: PathBuf, options: &ScanOptions) -> Self {
+        Checkpoint {
+            path,
+            settings: settings_line(options),
+            pending: Vec::new(),
+            last_write: Instant::now(),
+        }
+    }
+
+    // Records the hash of the file at `path`, which had the given metadata
+    // before it was read, so that it is not reused if it changed since
+    pub(crate) fn record(&mut self, path: &Path, metadata: &fs::Metadata, hash: &Hash) {
+        // Writing to a Vec cannot fail
+        let _ = write_plain_line(
+            &mut self.pending,
+            &to_hex(hash),
+            metadata.len(),
+            (metadata.mtime(), metadata.mtime_nsec()),
+            path,
+        );
+    }
+
+    pub(crate) fn write_if_due(&mut self) -> io::Result<()> {
+        if self.last_write.elapsed() < CHECKPOINT_INTERVAL {
+            return Ok(());
+        }
+        self.write()
+    }
+
+    // Replaces the file by one with the new lines added, through a temporary
+    // file, so that an interruption never leaves a partial checkpoint behind
+    // and readers need no lock. Writers hold an exclusive lock on
+    // `<path>.lock` from reading the file until the rename, so that
+    // processes sharing the file do not drop each other's lines. Lines
+    // written with other settings are dropped rather than mixed in.
+    pub(crate) fn write(&mut self) -> io::Result<()> {
+        self.last_write = Instant::now();
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let lock = fs::File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(with_suffix(&self.path, ".lock"))?;
+        lock.lock()?;
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        let header = format!("{}\n", self.settings);
+        let mut lines = match contents.strip_prefix(header.as_bytes()) {
+            Some(lines) => lines.to_vec(),
+            None => Vec::new(),
+        };
+        lines.append(&mut self.pending);
+        let temp = with_suffix(&self.path, ".tmp");
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(header.as_bytes())?;
+        file.write_all(&latest_lines(&lines))?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// Leaves out all but the last line for each path, which is the one
+// `PriorManifest::read` would use, so that a shared file does not grow with
+// every run
+fn latest_lines(contents: &[u8]) -> Vec<u8> {
+    let mut seen = HashSet::new();
+    let mut lines: Vec<&[u8]> = contents
+        .split(|&b| b == b'\n')
+        .rev()
+        .filter(|line| !line.is_empty())
+        .filter(|line| seen.insert(line.splitn(4, |&b| b == b' ').nth(3)))
+        .collect();
+    lines.reverse();
+    let mut latest = lines.join(&b'\n');
+    latest.push(b'\n');
+    latest
+}
+
+/// A file whose contents match a known hash.
+#[derive(Debug, Clone)]
+pub struct KnownFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: Hash,
+}
+
+/// Walks `paths` and returns the files whose contents have one of the
+/// `known` hashes, which are given by file size. Only files of a listed size
+/// are read.
+pub fn find_known_files(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+    known: &HashMap<u64, HashSet<Hash>>,
+) -> io::Result<Vec<KnownFile>> {
+    let filter = Filter::new(options)?;
+    let mut hashes = HashCache::new(options, Contents::Raw);
+    let mut found = Vec::new();
+
+    for root in &distinct_roots(paths) {
+        for entry in walk_root(root, options, &filter, &mut 0)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() || !filter.has_wanted_extension(entry.path()) {
+                continue;
+            }
+            let size = match entry.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(err) => {
+                    options.on_error.handle(entry.path(), err.into())?;
+                    continue;
+                }
+            };
+            let Some(known_hashes) = known
+                .get(&size)
+                .filter(|_| size > filter.min_size(entry.path()))
+            else {
+                continue;
+            };
+            match hashes.hash_uncached(entry.path(), size) {
+                Ok(hash) if known_hashes.contains(&hash) => found.push(KnownFile {
+                    path: entry.path().to_path_buf(),
+                    size,
+                    hash,
+                }),
+                Ok(_) => {}
+                Err(err) => options.on_error.handle(entry.path(), err)?,
+            }
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_paths_read_back_as_the_same_bytes() {
+        for name in [
+            &b"plain name.txt"[..],
+            b"caf\xc3\xa9",
+            b"caf\xe9",
+            b"two\nlines\r",
+            b"back\\slash \\x41",
+        ] {
+            let path = Path::new(std::ffi::OsStr::from_bytes(name));
+            let escaped = escape_path(path);
+            assert!(!escaped.contains(&b'\n'));
+            let escaped = String::from_utf8(escaped).unwrap();
+            assert_eq!(unescape_path(&escaped).as_deref(), Some(path));
+        }
+        assert_eq!(unescape_path("trailing\\"), None);
+    }
+}
@@ -0,0 +1,62 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Summary of a run, in the form exported to Prometheus.
+pub struct Metrics {
+    pub files_processed: u64,
+    pub bytes_reclaimable: u64,
+    pub actions_total: u64,
+    pub run_duration: Duration,
+}
+
+impl Metrics {
+    fn format(&self) -> String {
+        let metrics = [
+            (
+                "dedup_files_processed",
+                "Number of files considered",
+                self.files_processed.to_string(),
+            ),
+            (
+                "dedup_bytes_reclaimable",
+                "Bytes freed or freeable by handling the duplicates",
+                self.bytes_reclaimable.to_string(),
+            ),
+            (
+                "dedup_actions_total",
+                "Number of duplicates handled or found",
+                self.actions_total.to_string(),
+            ),
+            (
+                "dedup_run_duration_seconds",
+                "Duration of the run",
+                self.run_duration.as_secs_f64().to_string(),
+            ),
+        ];
+        let mut out = String::new();
+        for (name, help, value) in metrics {
+            out += &format!(
+                "# HELP {} {}\n# TYPE {} gauge\n{} {}\n",
+                name, help, name, name, value
+            );
+        }
+        out
+    }
+
+    /// Writes the metrics in the Prometheus text exposition format. The file
+    /// is written next to `path` first and then renamed, so that readers such
+    /// as the node_exporter textfile collector never see a partial file.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_path = OsString::from(path.as_os_str());
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(self.format().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    }
+}
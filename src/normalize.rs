@@ -0,0 +1,124 @@
+use crate::{eol, jpeg};
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+type OpenFn = dyn Fn(&Path) -> io::Result<Option<Box<dyn Read>>> + Send + Sync;
+type SizeFn = dyn Fn(&Path) -> io::Result<Option<u64>> + Send + Sync;
+
+/// A transform of file contents, applied before hashing: files it applies to
+/// are compared by what it yields rather than by their bytes. Such files are
+/// only duplicates of each other, not of files it leaves alone.
+///
+/// Files that match this way need not be byte for byte identical, so
+/// removing or linking them loses whatever the transform drops. The CLI only
+/// reports them; callers that change files should at least warn.
+#[derive(Clone)]
+pub struct Normalizer {
+    open: Arc<OpenFn>,
+    // Finds the length of the output without producing it, if that is cheaper
+    size: Option<Arc<SizeFn>>,
+}
+
+impl Normalizer {
+    /// Creates a transform from `open`, which opens the file at the given
+    /// path for reading the transformed contents, or returns `None` for
+    /// files the transform does not apply to. Every file it applies to is
+    /// read once in full to find the length of the output.
+    pub fn new<F>(open: F) -> Self
+    where
+        F: Fn(&Path) -> io::Result<Option<Box<dyn Read>>> + Send + Sync + 'static,
+    {
+        Normalizer {
+            open: Arc::new(open),
+            size: None,
+        }
+    }
+
+    /// JPEG files without their EXIF, XMP, IPTC and comment segments, so
+    /// that copies that only differ in, say, the timestamp or location
+    /// match.
+    pub fn strip_exif() -> Self {
+        Normalizer {
+            open: Arc::new(|path| {
+                if jpeg::is_jpeg(path) {
+                    jpeg::open_stripped(path).map(Some)
+                } else {
+                    Ok(None)
+                }
+            }),
+            size: Some(Arc::new(|path| {
+                if jpeg::is_jpeg(path) {
+                    jpeg::stripped_size(path)
+                } else {
+                    Ok(None)
+                }
+            })),
+        }
+    }
+
+    /// Text files, as recognized by their extension, with CRLF line endings
+    /// turned into LF, so that copies checked out on Windows and Unix match.
+    pub fn normalize_eol() -> Self {
+        Normalizer::new(|path| {
+            if eol::is_text(path) {
+                eol::open_normalized(path).map(Some)
+            } else {
+                Ok(None)
+            }
+        })
+    }
+
+    /// Applies the first of `normalizers` that applies to a file.
+    pub fn first_of(normalizers: Vec<Normalizer>) -> Self {
+        let normalizers = Arc::new(normalizers);
+        let for_size = normalizers.clone();
+        Normalizer {
+            open: Arc::new(move |path| {
+                for normalizer in normalizers.iter() {
+                    if let Some(reader) = (normalizer.open)(path)? {
+                        return Ok(Some(reader));
+                    }
+                }
+                Ok(None)
+            }),
+            size: Some(Arc::new(move |path| {
+                for normalizer in for_size.iter() {
+                    if let Some(size) = normalizer.size(path)? {
+                        return Ok(Some(size));
+                    }
+                }
+                Ok(None)
+            })),
+        }
+    }
+
+    // The length of the transformed contents of the file at `path`, or None
+    // if the transform does not apply to it
+    pub(crate) fn size(&self, path: &Path) -> io::Result<Option<u64>> {
+        if let Some(size) = &self.size {
+            return size(path);
+        }
+        match (self.open)(path)? {
+            Some(mut reader) => io::copy(&mut reader, &mut io::sink()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Opens the transformed contents of a file that `size` applied to
+    pub(crate) fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        (self.open)(path)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the transform no longer applies to this file",
+            )
+        })
+    }
+}
+
+impl fmt::Debug for Normalizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Normalizer")
+    }
+}
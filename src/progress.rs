@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+// Progress line on stderr. Only files that share their size with another file
+// are hashed, so only those count towards the total; the estimated time left
+// is extrapolated from the rate at which their bytes have been processed.
+pub(crate) struct Progress {
+    sizes: HashMap<u64, u64>,
+    total_bytes: u64,
+    done_bytes: u64,
+    start: Instant,
+    last_draw: Option<Instant>,
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+impl Progress {
+    pub(crate) fn new(sizes: HashMap<u64, u64>) -> Self {
+        let total_bytes = sizes
+            .iter()
+            .filter(|(_, &count)| count > 1)
+            .map(|(&size, &count)| size * count)
+            .sum();
+        Progress {
+            sizes,
+            total_bytes,
+            done_bytes: 0,
+            start: Instant::now(),
+            last_draw: None,
+        }
+    }
+
+    pub(crate) fn advance(&mut self, size: u64) {
+        if self.sizes.get(&size).is_some_and(|&count| count > 1) {
+            self.done_bytes += size;
+        }
+        if self
+            .last_draw
+            .is_some_and(|last_draw| last_draw.elapsed() < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+        self.draw();
+    }
+
+    fn draw(&self) {
+        let elapsed = self.start.elapsed();
+        let done = self.done_bytes.min(self.total_bytes);
+        let eta = if done == 0 {
+            "--:--:--".to_string()
+        } else {
+            let rate = done as f64 / elapsed.as_secs_f64().max(1e-9);
+            format_duration(Duration::from_secs_f64(
+                (self.total_bytes - done) as f64 / rate,
+            ))
+        };
+        let percent = if self.total_bytes == 0 {
+            100.0
+        } else {
+            done as f64 * 100.0 / self.total_bytes as f64
+        };
+        eprint!(
+            "\r{:5.1}% of {} MiB, elapsed {}, left {}  ",
+            percent,
+            self.total_bytes / (1024 * 1024),
+            format_duration(elapsed),
+            eta
+        );
+        let _ = io::stderr().flush();
+    }
+
+    // Clears the progress line
+    pub(crate) fn finish(&self) {
+        eprint!("\r{:60}\r", "");
+    }
+}
@@ -0,0 +1,40 @@
+use anyhow::Context;
+use dedup::{from_hex, Hash};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+// Time allowed for connecting and downloading the whole manifest
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Entry of a reference manifest: a JSON array of these objects, e.g.
+/// `[{"size": 1024, "hash": "<hex SHA-256>"}]`.
+#[derive(Deserialize)]
+struct Entry {
+    size: u64,
+    hash: String,
+}
+
+/// Downloads a reference manifest and returns its hashes by file size.
+pub fn fetch_reference(url: &str) -> anyhow::Result<HashMap<u64, HashSet<Hash>>> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(TIMEOUT)
+        .build()?;
+    let response = client
+        .get(url)
+        .send()
+        .with_context(|| format!("Could not reach {}", url))?
+        .error_for_status()
+        .with_context(|| format!("The server refused the request for {}", url))?;
+    let entries: Vec<Entry> = response
+        .json()
+        .with_context(|| format!("{} is not a JSON reference manifest", url))?;
+
+    let mut hashes: HashMap<u64, HashSet<Hash>> = HashMap::new();
+    for entry in entries {
+        let hash = from_hex(&entry.hash)
+            .with_context(|| format!("Invalid hash {:?} in the reference manifest", entry.hash))?;
+        hashes.entry(entry.size).or_default().insert(hash);
+    }
+    Ok(hashes)
+}
@@ -0,0 +1,59 @@
+use dedup::fixture::create_fixture;
+use dedup::{find_duplicates, KeeperChoice, ScanOptions};
+use std::fs;
+use std::path::PathBuf;
+
+fn check(ok: bool, description: &str) -> bool {
+    println!("{}  {}", if ok { "PASS" } else { "FAIL" }, description);
+    ok
+}
+
+/// Creates a temporary tree with known duplicates, runs the detection on it
+/// and prints PASS/FAIL for each expectation. Returns whether all passed.
+pub fn run() -> anyhow::Result<bool> {
+    let root = std::env::temp_dir().join(format!("dedup-selftest-{}", std::process::id()));
+    create_fixture(&root)?;
+    let scan = find_duplicates(std::slice::from_ref(&root), &ScanOptions::default(), |_| {
+        KeeperChoice::Keep(0)
+    });
+    fs::remove_dir_all(&root)?;
+    let scan = scan?;
+
+    let mut found: Vec<Vec<PathBuf>> = scan
+        .groups
+        .iter()
+        .map(|group| {
+            let mut paths: Vec<PathBuf> = group
+                .paths
+                .iter()
+                .map(|path| path.strip_prefix(&root).unwrap().to_path_buf())
+                .collect();
+            paths.sort();
+            paths
+        })
+        .collect();
+
+    let expected = [
+        (["small/a.txt", "small/nested/b.txt"], "small files"),
+        (["big/a", "big/b"], "files spanning several blocks"),
+        (["block/a", "block/b"], "files of exactly one block"),
+    ];
+    let mut passed = true;
+    for (paths, description) in expected {
+        let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+        let position = found.iter().position(|group| *group == paths);
+        passed &= check(position.is_some(), &format!("duplicate {}", description));
+        if let Some(position) = position {
+            found.remove(position);
+        }
+    }
+    passed &= check(
+        found.is_empty(),
+        "empty files and files differing only at the end are not duplicates",
+    );
+    for group in &found {
+        println!("      unexpected group: {:?}", group);
+    }
+
+    Ok(passed)
+}
@@ -0,0 +1,120 @@
+use crate::filter::Filter;
+use crate::{walk_root, ScanOptions};
+use std::io;
+use std::path::PathBuf;
+
+// Larger files are left out, as they are read into memory whole
+const MAX_SIZE: u64 = 16 << 20;
+
+/// Files whose contents are similar, though not necessarily identical.
+#[derive(Debug)]
+pub struct SimilarGroup {
+    pub paths: Vec<PathBuf>,
+    /// Lowest fuzzy hash match score (0 to 100) of the pairs of files that
+    /// connect the group.
+    pub min_score: u8,
+}
+
+// Union-find over file indices, remembering the lowest score per component
+struct Components {
+    parent: Vec<usize>,
+    min_score: Vec<u8>,
+}
+
+impl Components {
+    fn new(len: usize) -> Self {
+        Components {
+            parent: (0..len).collect(),
+            min_score: vec![u8::MAX; len],
+        }
+    }
+
+    fn root(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    fn join(&mut self, a: usize, b: usize, score: u8) {
+        let (a, b) = (self.root(a), self.root(b));
+        let min_score = self.min_score[a].min(self.min_score[b]).min(score);
+        self.parent[b] = a;
+        self.min_score[a] = min_score;
+    }
+}
+
+/// Walks `paths` and groups files whose ssdeep fuzzy hashes match with a
+/// score of at least `threshold` (0 to 100), e.g. slightly edited copies of a
+/// document. Files in a group are connected by such matches, but two of them
+/// may match less well. Every pair of files is compared, so this is meant for
+/// document collections rather than whole disks. Empty files and files over
+/// 16 MiB are skipped; files of a few KiB or less rarely match at all.
+pub fn find_similar_files(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+    threshold: u8,
+) -> io::Result<Vec<SimilarGroup>> {
+    let filter = Filter::new(options)?;
+    let mut files = Vec::new();
+    for root in paths {
+        for entry in walk_root(root, options, &filter, &mut 0)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() || !filter.has_wanted_extension(entry.path()) {
+                continue;
+            }
+            let size = match entry.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(err) => {
+                    options.on_error.handle(entry.path(), err.into())?;
+                    continue;
+                }
+            };
+            if size == 0 || size <= filter.min_size(entry.path()) || size > MAX_SIZE {
+                continue;
+            }
+            let hash = std::fs::read(entry.path()).and_then(|contents| {
+                ssdeep::hash(&contents).map_err(|err| io::Error::other(err.to_string()))
+            });
+            match hash {
+                Ok(hash) => files.push((entry.into_path(), hash)),
+                Err(err) => options.on_error.handle(entry.path(), err)?,
+            }
+        }
+    }
+
+    let mut components = Components::new(files.len());
+    for i in 0..files.len() {
+        for j in i + 1..files.len() {
+            let score = ssdeep::compare(&files[i].1, &files[j].1).unwrap_or(0);
+            if score >= threshold {
+                components.join(i, j, score);
+            }
+        }
+    }
+
+    let mut groups: Vec<Option<SimilarGroup>> = (0..files.len()).map(|_| None).collect();
+    for (i, (path, _)) in files.into_iter().enumerate() {
+        let root = components.root(i);
+        let min_score = components.min_score[root];
+        groups[root]
+            .get_or_insert_with(|| SimilarGroup {
+                paths: Vec::new(),
+                min_score,
+            })
+            .paths
+            .push(path);
+    }
+    Ok(groups
+        .into_iter()
+        .flatten()
+        .filter(|group| group.paths.len() > 1)
+        .collect())
+}
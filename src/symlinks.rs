@@ -0,0 +1,105 @@
+use crate::filter::Filter;
+use crate::{distinct_roots, walk_root, ScanOptions};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// A set of symlinks that point to the same file or directory.
+#[derive(Debug)]
+pub struct SymlinkGroup {
+    /// Normalized, absolute target of the links.
+    pub target: PathBuf,
+    pub links: Vec<PathBuf>,
+}
+
+// Removes `.` and `..` components without touching the file system
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+// Returns the absolute target of the symlink at `link`. Links that can be
+// followed are fully resolved, so that chains of links and links through
+// symlinked directories compare equal; dangling links are normalized
+// lexically.
+fn link_target(link: &Path) -> io::Result<PathBuf> {
+    if let Ok(target) = link.canonicalize() {
+        return Ok(target);
+    }
+    let target = fs::read_link(link)?;
+    let parent = match link.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Ok(normalize(&parent.canonicalize()?.join(target)))
+}
+
+/// Walks `paths` and collects groups of symlinks with the same target. The
+/// links themselves are not followed. Links are matched against
+/// `extensions` by their own name, and links to files against a nonzero
+/// minimum size by the size of the file.
+pub fn find_duplicate_symlinks(
+    paths: &[PathBuf],
+    options: &ScanOptions,
+) -> io::Result<Vec<SymlinkGroup>> {
+    let filter = Filter::new(options)?;
+    let mut groups: Vec<SymlinkGroup> = Vec::new();
+    let mut group_of: HashMap<PathBuf, usize> = HashMap::new();
+
+    for root in &distinct_roots(paths) {
+        for entry in walk_root(root, options, &filter, &mut 0)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    continue;
+                }
+            };
+            if !entry.path_is_symlink()
+                || entry.depth() == 0
+                || !filter.has_wanted_extension(entry.path())
+            {
+                continue;
+            }
+            // Links to files are filtered by the size of the file, if a
+            // minimum is set: links to empty files are redundant as well
+            let min_size = filter.min_size(entry.path());
+            if min_size > 0
+                && fs::metadata(entry.path())
+                    .is_ok_and(|metadata| metadata.is_file() && metadata.len() <= min_size)
+            {
+                continue;
+            }
+            let target = match link_target(entry.path()) {
+                Ok(target) => target,
+                Err(err) => {
+                    options.on_error.handle(entry.path(), err)?;
+                    continue;
+                }
+            };
+            match group_of.get(&target) {
+                Some(&i) => groups[i].links.push(entry.into_path()),
+                None => {
+                    group_of.insert(target.clone(), groups.len());
+                    groups.push(SymlinkGroup {
+                        target,
+                        links: vec![entry.into_path()],
+                    });
+                }
+            }
+        }
+    }
+
+    groups.retain(|group| group.links.len() > 1);
+    Ok(groups)
+}
@@ -0,0 +1,275 @@
+use crate::config::Config;
+use crate::{
+    build_plan, choose_keeper, exec_command, json, link_reaches, protection, shell_quote,
+    verify_groups, Options, Stats,
+};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use dedup::fixture::{create_fixture, TempDir};
+use dedup::hasher::{Hasher, HasherFactory};
+use dedup::{find_duplicates, to_hex, Action, DuplicateGroup, Hash, KeeperChoice, ScanOptions};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+// Gives every input the same hash, so that all files of a size look alike
+struct Constant;
+
+impl Hasher for Constant {
+    fn update(&mut self, _bytes: &[u8]) {}
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        vec![0; 32]
+    }
+}
+
+// --parallel-verify must come to the same result on any number of threads.
+// With the constant hash, the files that differ at the end are grouped with
+// their namesakes, so there are problems to find.
+#[test]
+fn parallel_verify_is_independent_of_thread_count() -> anyhow::Result<()> {
+    let root = TempDir::new("parallel-verify");
+    create_fixture(&root.0)?;
+    let options = ScanOptions {
+        hasher: HasherFactory::new(|| Box::new(Constant)),
+        ..ScanOptions::default()
+    };
+    let scan = find_duplicates(std::slice::from_ref(&root.0), &options, |_| {
+        KeeperChoice::Keep(0)
+    })?;
+    let options = Options::parse_from(["dedup", "--verify-all", "--parallel-verify", "."]);
+    let verified = [1, 2, 4]
+        .into_iter()
+        .map(|threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()?;
+            let mut stats = Stats::default();
+            let problems =
+                pool.install(|| verify_groups(&scan, &options, &HashSet::new(), &mut stats));
+            let mut groups: Vec<usize> = problems.into_keys().collect();
+            groups.sort();
+            Ok((groups, stats.verified_bytes))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert_eq!(verified[0].0.len(), 2);
+    assert!(verified.iter().all(|result| *result == verified[0]));
+    Ok(())
+}
+
+// The last check before changing a file must refuse protected files,
+// whatever the action loop decided
+#[test]
+fn protected_files_are_never_changed() -> anyhow::Result<()> {
+    let root = TempDir::new("protection");
+    let root = &root.0;
+    fs::create_dir_all(root.join("in"))?;
+    for name in ["a", "b", "in/c"] {
+        fs::write(root.join(name), b"protected\n")?;
+    }
+    let in_dir = root.join("in").canonicalize()?;
+    let group = DuplicateGroup {
+        size: 10,
+        hash: Hash::default(),
+        paths: vec![root.join("a"), root.join("b"), root.join("in/c")],
+    };
+    let action = |duplicate: &str| Action {
+        group: 0,
+        duplicate: root.join(duplicate),
+        keeper: root.join("a"),
+        size: 10,
+    };
+    let plain = Options::parse_from(["dedup", "--remove", "."]);
+    let mut writable = Options::parse_from(["dedup", "--remove", "."]);
+    writable.writable = vec![in_dir.clone()];
+    let mut act_under = Options::parse_from(["dedup", "--remove", "."]);
+    act_under.act_under = Some(in_dir);
+    let none = HashSet::new();
+    let listed = HashSet::from([Hash::default()]);
+
+    assert!(protection(&action("in/../a"), &group, &plain, &none).is_some());
+    assert!(protection(&action("b"), &group, &plain, &listed).is_some());
+    assert!(protection(&action("b"), &group, &writable, &none).is_some());
+    assert!(protection(&action("b"), &group, &act_under, &none).is_some());
+    assert!(protection(&action("b"), &group, &plain, &none).is_none());
+    assert!(protection(&action("in/c"), &group, &writable, &none).is_none());
+    assert!(protection(&action("in/c"), &group, &act_under, &none).is_none());
+    Ok(())
+}
+
+// The longest name is in the last root, and the group lists the first root's
+// copy in every position
+#[test]
+fn first_root_wins_keeps_the_copy_under_the_first_root() {
+    let options = Options::parse_from([
+        "dedup",
+        "--first-root-wins",
+        "--keep",
+        "longest-name",
+        "one",
+        "two",
+        "three",
+    ]);
+    let copies = ["one/a", "two/longer", "three/longest"].map(PathBuf::from);
+    for rotation in 0..copies.len() {
+        let mut paths = copies.to_vec();
+        paths.rotate_left(rotation);
+        let group = DuplicateGroup {
+            size: 10,
+            hash: Hash::default(),
+            paths,
+        };
+        let keeper = match choose_keeper(&group, &options) {
+            KeeperChoice::Keep(i) => Some(group.paths[i].clone()),
+            KeeperChoice::Skip => None,
+        };
+        assert_eq!(keeper, Some(copies[0].clone()));
+    }
+}
+
+#[test]
+fn first_root_wins_works_with_deterministic() {
+    let options =
+        Options::try_parse_from(["dedup", "--first-root-wins", "--deterministic", "b", "a"]);
+    assert!(options.is_ok_and(|options| options.paths == [PathBuf::from("b"), PathBuf::from("a")]));
+}
+
+// Without `=`, the next argument is a directory to search
+#[test]
+fn bare_hash_cache_takes_no_value() {
+    let options = Options::parse_from(["dedup", "--hash-cache", "photos"]);
+    assert_eq!(options.hash_cache_arg, Some(None));
+    assert_eq!(options.paths, [PathBuf::from("photos")]);
+    let options = Options::parse_from(["dedup", "--hash-cache=cache", "photos"]);
+    assert_eq!(options.hash_cache_arg, Some(Some(PathBuf::from("cache"))));
+}
+
+#[test]
+fn script_and_exec_arguments_are_quoted() {
+    assert_eq!(
+        shell_quote(OsStr::new("it's a $file")),
+        b"'it'\\''s a $file'"
+    );
+    let group = DuplicateGroup {
+        size: 10,
+        hash: Hash::default(),
+        paths: vec![PathBuf::from("a b"), PathBuf::from("it's")],
+    };
+    let command = exec_command(
+        "log {size} {dup} {keeper} {other}",
+        &Action {
+            group: 0,
+            duplicate: PathBuf::from("it's"),
+            keeper: PathBuf::from("a b"),
+            size: 10,
+        },
+        &group,
+    );
+    assert_eq!(command, b"log 10 'it'\\''s' 'a b' {other}");
+}
+
+// Links are created next to the duplicate, so their targets are relative to
+// its directory
+#[test]
+fn link_check_accepts_only_the_keeper() -> anyhow::Result<()> {
+    let root = TempDir::new("link-check");
+    let root = &root.0;
+    let (keeper, duplicate) = (root.join("keeper"), root.join("a/b/dup"));
+    fs::create_dir_all(root.join("a/b"))?;
+    fs::write(&keeper, b"keeper\n")?;
+    fs::write(root.join("a/keeper"), b"keeper\n")?;
+    fs::write(&duplicate, b"keeper\n")?;
+    std::os::unix::fs::symlink("loop", root.join("a/b/loop"))?;
+    let rel = dedup::relative_path(&duplicate, &keeper)?;
+    assert!(link_reaches(&duplicate, &rel, &keeper)?);
+    assert!(!link_reaches(&duplicate, Path::new("../keeper"), &keeper)?);
+    assert!(link_reaches(&duplicate, Path::new("loop"), &keeper).is_err());
+    Ok(())
+}
+
+// Latin-1 names are not valid UTF-8
+#[test]
+fn non_utf8_names_are_reported_with_their_bytes() -> anyhow::Result<()> {
+    let root = TempDir::new("non-utf8");
+    for name in [&b"caf\xe9"[..], b"caf\xe9 (copy)"] {
+        fs::write(root.0.join(OsStr::from_bytes(name)), b"latin-1\n")?;
+    }
+    let scan = find_duplicates(
+        std::slice::from_ref(&root.0),
+        &ScanOptions::default(),
+        |_| KeeperChoice::Keep(0),
+    )?;
+    let names: Vec<(String, Option<String>)> = scan
+        .groups
+        .iter()
+        .flat_map(|group| &group.paths)
+        .filter_map(|path| path.file_name().map(Path::new))
+        .map(|name| (json::path_string(name), json::raw_path(name)))
+        .collect();
+    assert_eq!(
+        names,
+        [
+            ("caf\u{fffd}".to_string(), Some("Y2Fm6Q==".to_string())),
+            (
+                "caf\u{fffd} (copy)".to_string(),
+                Some("Y2Fm6SAoY29weSk=".to_string()),
+            ),
+        ]
+    );
+    Ok(())
+}
+
+// Options from a config file as applied to the given command line
+fn with_config(config: &str, args: &[&str]) -> Options {
+    let matches = Options::command().get_matches_from(args);
+    let mut options = Options::from_arg_matches(&matches).unwrap();
+    let config: Config = toml::from_str(config).unwrap();
+    config.apply(&mut options, &matches);
+    options
+}
+
+// Options given on the command line also override the settings of the config
+// file that they conflict with
+#[test]
+fn config_does_not_set_options_conflicting_with_the_command_line() {
+    let remove = "mode = \"remove\"\nignore-trailing = 8";
+    let options = with_config(remove, &["dedup", "."]);
+    assert!(options.remove);
+    assert_eq!(options.ignore_trailing, 8);
+    for report_only in ["--normalize-eol", "--strip-exif", "--dirs", "--size-only"] {
+        let options = with_config(remove, &["dedup", report_only, "."]);
+        assert!(
+            !options.remove && !options.replace_by_symlink,
+            "{}",
+            report_only
+        );
+    }
+    let options = with_config(remove, &["dedup", "--strip-exif", "."]);
+    assert_eq!(options.ignore_trailing, 0);
+    let options = with_config("mode = \"report\"", &["dedup", "--remove", "."]);
+    assert!(options.remove);
+}
+
+// The plan is a record of the run, so --hash-display-len does not shorten
+// its hashes
+#[test]
+fn plan_has_full_hashes() -> anyhow::Result<()> {
+    let root = TempDir::new("plan-hashes");
+    create_fixture(&root.0)?;
+    let scan = find_duplicates(
+        std::slice::from_ref(&root.0),
+        &ScanOptions::default(),
+        |_| KeeperChoice::Keep(0),
+    )?;
+    let options = Options::parse_from(["dedup", "--hash-display-len", "12", "."]);
+    let plan = build_plan(&scan, &options, &HashSet::new());
+    assert!(!plan.actions.is_empty());
+    for action in &plan.actions {
+        assert!(scan
+            .groups
+            .iter()
+            .any(|group| to_hex(&group.hash) == action.hash));
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use crate::json::Report;
+use std::io::{self, Write};
+
+// Escapes text for use in element content and attribute values. Control
+// characters other than tab and newline cannot appear in XML 1.0 at all, not
+// even as character references, so they are replaced by U+FFFD.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // As references, so that attribute values keep them too
+            '\t' => escaped.push_str("&#9;"),
+            '\n' => escaped.push_str("&#10;"),
+            c if c.is_control() => escaped.push('\u{fffd}'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Prints the report as XML, in this structure:
+///
+/// ```xml
+/// <duplicates files-processed="3" duplicates="1" saved-bytes="6">
+///   <group size="6" hash="...">
+///     <path>a</path>
+///     <path>b</path>
+///   </group>
+///   <error path="c" kind="permission denied">...</error>
+/// </duplicates>
+/// ```
+pub fn print(report: &Report) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<duplicates files-processed="{}" duplicates="{}" saved-bytes="{}">"#,
+        report.files_processed, report.duplicates, report.saved_bytes
+    )?;
+    for group in &report.groups {
+        writeln!(
+            out,
+            r#"  <group size="{}" hash="{}">"#,
+            group.size,
+            escape(&group.hash)
+        )?;
+        for path in &group.paths {
+            writeln!(out, "    <path>{}</path>", escape(path))?;
+        }
+        writeln!(out, "  </group>")?;
+    }
+    for error in &report.errors {
+        writeln!(
+            out,
+            r#"  <error path="{}" kind="{}">{}</error>"#,
+            escape(&error.path),
+            escape(&error.kind),
+            escape(&error.message)
+        )?;
+    }
+    writeln!(out, "</duplicates>")
+}
@@ -0,0 +1,292 @@
+use dedup::dirs::find_duplicate_dirs;
+use dedup::fixture::{create_fixture, TempDir};
+use dedup::hasher::HasherFactory;
+use dedup::manifest::PriorManifest;
+use dedup::{find_duplicates, to_hex, KeeperChoice, ScanOptions};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+fn dedup() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_dedup"))
+}
+
+// Two processes share a hash cache at the same time, each adding the hashes
+// of its own tree. The files are rewritten every round, so that each round
+// both write to the cache. Lines must not get lost, so that a scan of both
+// trees then reads nothing in full.
+#[test]
+fn concurrent_runs_share_a_hash_cache() -> std::io::Result<()> {
+    let dir = TempDir::new("hash-cache");
+    let cache = dir.0.join("cache");
+    let (left, right) = (dir.0.join("left"), dir.0.join("right"));
+    for _ in 0..4 {
+        create_fixture(&left)?;
+        create_fixture(&right)?;
+        let children = [&left, &right].map(|tree| {
+            let mut cache_arg = std::ffi::OsString::from("--hash-cache=");
+            cache_arg.push(&cache);
+            dedup()
+                .arg(cache_arg)
+                .arg(tree)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+        });
+        for child in children {
+            assert!(child?.wait()?.success());
+        }
+    }
+    let options = ScanOptions {
+        since: Some(Arc::new(PriorManifest::read(&cache)?)),
+        ..ScanOptions::default()
+    };
+    let shared = find_duplicates(&[left, right], &options, |_| KeeperChoice::Keep(0))?;
+    assert_eq!(shared.full_hash_reads, 0);
+    assert!(shared.reused_hashes > 0);
+    Ok(())
+}
+
+// Nests `levels` directories with long names in `dir`; returns the innermost
+fn nest(dir: &Path, levels: usize) -> std::io::Result<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for _ in 0..levels {
+        path.push("d".repeat(200));
+    }
+    std::fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+// Directories nested deeper than PATH_MAX (4096 bytes on Linux) cannot be
+// opened by their full path, nor created by it. Two chains of half that
+// depth are created, and the second is moved to the end of the first.
+#[test]
+fn paths_over_the_length_limit_are_skipped() -> std::io::Result<()> {
+    let dir = TempDir::new("deep");
+    let (tree, outer) = (dir.0.join("tree"), dir.0.join("outer"));
+    std::fs::create_dir(&tree)?;
+    std::fs::write(tree.join("a"), b"shallow\n")?;
+    std::fs::write(tree.join("b"), b"shallow\n")?;
+    let middle = nest(&tree, 12)?;
+    let deepest = nest(&outer, 12)?;
+    std::fs::write(deepest.join("a"), b"deep\n")?;
+    std::fs::write(deepest.join("b"), b"deep\n")?;
+    let moved = outer.join("d".repeat(200));
+    std::fs::rename(&moved, middle.join(moved.file_name().unwrap()))?;
+
+    let output = dedup().arg(&tree).output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("too long"), "{}", stderr);
+
+    let scan = find_duplicates(std::slice::from_ref(&tree), &ScanOptions::default(), |_| {
+        KeeperChoice::Keep(0)
+    })?;
+    assert_eq!(scan.groups.len(), 1);
+    assert_eq!(scan.groups[0].paths, [tree.join("a"), tree.join("b")]);
+    assert!(!scan.errors.is_empty());
+    Ok(())
+}
+
+// A directory that cannot be read does not end the search for duplicate
+// directories, but neither it nor the directories containing it can be
+// reported
+#[test]
+fn dirs_skip_unreadable_directories() -> std::io::Result<()> {
+    let dir = TempDir::new("deep-dirs");
+    let (tree, outer) = (dir.0.join("tree"), dir.0.join("outer"));
+    for copy in ["a", "b"] {
+        std::fs::create_dir_all(tree.join(copy))?;
+        std::fs::write(tree.join(copy).join("file"), b"shallow\n")?;
+    }
+    let middle = nest(&tree.join("deep"), 12)?;
+    nest(&outer, 12)?;
+    let moved = outer.join("d".repeat(200));
+    std::fs::rename(&moved, middle.join(moved.file_name().unwrap()))?;
+
+    let output = dedup().arg("--dirs").arg(&tree).output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("too long"), "{}", stderr);
+
+    let groups = find_duplicate_dirs(std::slice::from_ref(&tree), &ScanOptions::default())?;
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].paths, [tree.join("a"), tree.join("b")]);
+    Ok(())
+}
+
+// --normalize-eol only reports, so a mode from the config file does not
+// apply: b-crlf.txt differs from a-lf.txt and must not be removed
+#[test]
+fn config_mode_does_not_apply_to_report_only_options() -> std::io::Result<()> {
+    let dir = TempDir::new("config-mode");
+    let tree = dir.0.join("t");
+    std::fs::create_dir(&tree)?;
+    std::fs::write(tree.join("a-lf.txt"), b"a\nb\n")?;
+    std::fs::write(tree.join("b-crlf.txt"), b"a\r\nb\r\n")?;
+    std::fs::write(dir.0.join(".dedup.toml"), "mode = \"remove\"\n")?;
+
+    let output = dedup()
+        .current_dir(&dir.0)
+        .args(["--normalize-eol", "t"])
+        .output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(tree.join("b-crlf.txt").exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Removing them would save"), "{}", stdout);
+    Ok(())
+}
+
+// The checkpoint left behind by a run with --ignore-trailing is not resumed
+// from by one without, and a completed run removes it with its lock file
+#[test]
+fn checkpoint_with_other_settings_is_not_resumed() -> std::io::Result<()> {
+    let dir = TempDir::new("checkpoint-settings");
+    let tree = dir.0.join("tree");
+    std::fs::create_dir(&tree)?;
+    std::fs::write(tree.join("a"), b"contents, footer 1")?;
+    std::fs::write(tree.join("b"), b"contents, footer 2")?;
+    let checkpoint = dir.0.join("checkpoint");
+    let options = ScanOptions {
+        ignore_trailing: 8,
+        checkpoint: Some(checkpoint.clone()),
+        no_short_hash: true,
+        ..ScanOptions::default()
+    };
+    find_duplicates(std::slice::from_ref(&tree), &options, |_| {
+        KeeperChoice::Keep(0)
+    })?;
+    assert!(checkpoint.exists());
+
+    let output = dedup()
+        .arg("--checkpoint")
+        .arg(&checkpoint)
+        .arg(&tree)
+        .output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Not resuming"), "{}", stderr);
+    assert!(!checkpoint.exists());
+    assert!(!dir.0.join("checkpoint.lock").exists());
+    Ok(())
+}
+
+#[test]
+fn algorithm_is_used_for_the_hash_manifest() -> std::io::Result<()> {
+    let dir = TempDir::new("algorithm");
+    let tree = dir.0.join("tree");
+    std::fs::create_dir(&tree)?;
+    std::fs::write(tree.join("a"), b"hello\n")?;
+    let manifest = dir.0.join("manifest");
+
+    let output = dedup()
+        .args(["--algorithm", "blake3", "--hash-manifest"])
+        .arg(&manifest)
+        .arg(&tree)
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let blake3 = HasherFactory::by_name("blake3").unwrap();
+    let hash = to_hex(&blake3.hash_bytes(b"hello\n"));
+    let contents = std::fs::read_to_string(&manifest)?;
+    assert!(
+        contents.lines().any(|line| line.starts_with(&hash)),
+        "{}",
+        contents
+    );
+
+    let options = ScanOptions {
+        hasher: blake3,
+        ..ScanOptions::default()
+    };
+    assert!(PriorManifest::read(&manifest)?.matches(&options));
+    assert!(!PriorManifest::read(&manifest)?.matches(&ScanOptions::default()));
+    Ok(())
+}
+
+// Every hash function that --algorithm accepts is measured by `dedup bench`
+#[test]
+fn bench_measures_every_algorithm() -> std::io::Result<()> {
+    let dir = TempDir::new("bench");
+    create_fixture(&dir.0)?;
+    let output = dedup().arg("bench").arg(&dir.0).output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for (name, _) in HasherFactory::builtin() {
+        assert!(
+            stdout.lines().any(|line| line.starts_with(name)),
+            "{}",
+            stdout
+        );
+        let output = dedup().args(["--algorithm", name]).arg(&dir.0).output()?;
+        assert!(output.status.success(), "{}", name);
+    }
+    Ok(())
+}
+
+// Only the duplicates that were removed before --max-actions stopped the run
+// are recorded as not kept
+#[cfg(feature = "sqlite")]
+#[test]
+fn db_records_the_duplicates_actually_removed() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("db-kept");
+    let tree = dir.0.join("tree");
+    create_fixture(&tree)?;
+    let db = dir.0.join("dedup.db");
+
+    let output = dedup()
+        .args(["--remove", "--max-actions", "1", "--db"])
+        .arg(&db)
+        .arg(&tree)
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let connection = rusqlite::Connection::open(&db)?;
+    let replaced: Vec<String> = connection
+        .prepare("SELECT path FROM files WHERE NOT kept")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    assert_eq!(replaced.len(), 1);
+    assert!(!Path::new(&replaced[0]).exists());
+    Ok(())
+}
+
+// Once the largest duplicate has met the --free-target, the smaller ones are
+// reported, but left alone
+#[test]
+fn free_target_reports_the_rest_without_changing_it() -> std::io::Result<()> {
+    let dir = TempDir::new("free-target");
+    create_fixture(&dir.0)?;
+
+    let output = dedup()
+        .args(["--remove", "--free-target", "1"])
+        .arg(&dir.0)
+        .output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    let big_left = ["a", "b"]
+        .iter()
+        .filter(|name| dir.0.join("big").join(name).exists())
+        .count();
+    assert_eq!(big_left, 1);
+    for name in ["block/a", "block/b", "small/a.txt", "small/nested/b.txt"] {
+        assert!(dir.0.join(name).exists(), "{}", name);
+    }
+    assert!(
+        stdout.contains("Only reported 2 more duplicates"),
+        "{}",
+        stdout
+    );
+    Ok(())
+}
@@ -0,0 +1,545 @@
+use dedup::dirs::find_duplicate_dirs;
+use dedup::fixture::{create_fixture, pattern, TempDir, BLOCK_LEN};
+use dedup::hasher::{Hasher, HasherFactory};
+use dedup::manifest::{write_hash_manifest, ManifestFormat, PriorManifest};
+use dedup::normalize::Normalizer;
+use dedup::{find_duplicates, KeeperChoice, Scan, ScanOptions};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Number of groups of duplicates in the fixture
+const FIXTURE_GROUPS: usize = 3;
+
+// 64-bit FNV-1a, a deliberately simple stand-in for a custom hash function
+struct Fnv1a(u64);
+
+impl Hasher for Fnv1a {
+    fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.to_be_bytes().to_vec()
+    }
+}
+
+fn fnv1a() -> HasherFactory {
+    HasherFactory::new(|| Box::new(Fnv1a(0xcbf29ce484222325)))
+}
+
+fn scan(root: &Path, options: &ScanOptions) -> io::Result<Scan> {
+    find_duplicates(&[root.to_path_buf()], options, |_| KeeperChoice::Keep(0))
+}
+
+fn scan_fixture(name: &str, options: &ScanOptions) -> io::Result<Scan> {
+    let root = TempDir::new(name);
+    create_fixture(&root.0)?;
+    scan(&root.0, options)
+}
+
+// File names in each group, sorted within the group
+fn group_names(scan: &Scan) -> Vec<Vec<&OsStr>> {
+    scan.groups
+        .iter()
+        .map(|group| {
+            let mut names: Vec<&OsStr> = group
+                .paths
+                .iter()
+                .filter_map(|path| path.file_name())
+                .collect();
+            names.sort();
+            names
+        })
+        .collect()
+}
+
+#[test]
+fn finds_the_fixture_duplicates() -> io::Result<()> {
+    let root = TempDir::new("fixture");
+    create_fixture(&root.0)?;
+    let scan = scan(&root.0, &ScanOptions::default())?;
+    let mut found: Vec<Vec<PathBuf>> = scan
+        .groups
+        .iter()
+        .map(|group| {
+            let mut paths: Vec<PathBuf> = group
+                .paths
+                .iter()
+                .map(|path| path.strip_prefix(&root.0).unwrap().to_path_buf())
+                .collect();
+            paths.sort();
+            paths
+        })
+        .collect();
+    found.sort();
+    let expected: Vec<Vec<PathBuf>> = [
+        ["big/a", "big/b"],
+        ["block/a", "block/b"],
+        ["small/a.txt", "small/nested/b.txt"],
+    ]
+    .iter()
+    .map(|paths| paths.iter().map(PathBuf::from).collect())
+    .collect();
+    assert_eq!(found, expected);
+    Ok(())
+}
+
+#[test]
+fn min_size_leaves_out_files_at_or_below_it() -> io::Result<()> {
+    let min_size = BLOCK_LEN as u64;
+    let options = ScanOptions {
+        min_size,
+        ..ScanOptions::default()
+    };
+    let scan = scan_fixture("min-size", &options)?;
+    assert_eq!(scan.groups.len(), 1);
+    assert!(scan
+        .groups
+        .iter()
+        .all(|group| group.size > min_size && group.paths.len() == 2));
+    Ok(())
+}
+
+#[test]
+fn files_within_one_block_are_read_once() -> io::Result<()> {
+    let root = TempDir::new("one-block");
+    fs::write(root.0.join("a"), b"small\n")?;
+    fs::write(root.0.join("b"), b"small\n")?;
+    let scan = scan(&root.0, &ScanOptions::default())?;
+    assert_eq!(scan.groups.len(), 1);
+    assert_eq!(scan.full_hash_reads, 0);
+    Ok(())
+}
+
+#[test]
+fn custom_hasher_finds_the_same_duplicates() -> io::Result<()> {
+    let options = ScanOptions {
+        hasher: fnv1a(),
+        ..ScanOptions::default()
+    };
+    assert_eq!(
+        scan_fixture("custom-hasher", &options)?.groups.len(),
+        FIXTURE_GROUPS
+    );
+    Ok(())
+}
+
+#[test]
+fn builtin_hashers_find_the_same_duplicates() -> io::Result<()> {
+    for (name, hasher) in HasherFactory::builtin() {
+        let options = ScanOptions {
+            hasher,
+            ..ScanOptions::default()
+        };
+        let scan = scan_fixture(&format!("builtin-hasher-{}", name), &options)?;
+        assert_eq!(scan.groups.len(), FIXTURE_GROUPS, "{}", name);
+    }
+    Ok(())
+}
+
+#[test]
+fn two_passes_find_the_same_duplicates() -> io::Result<()> {
+    let options = ScanOptions {
+        two_pass: true,
+        ..ScanOptions::default()
+    };
+    assert_eq!(
+        scan_fixture("two-pass", &options)?.groups.len(),
+        FIXTURE_GROUPS
+    );
+    Ok(())
+}
+
+#[test]
+fn skipping_the_short_hash_finds_the_same_duplicates() -> io::Result<()> {
+    let options = ScanOptions {
+        no_short_hash: true,
+        ..ScanOptions::default()
+    };
+    assert_eq!(
+        scan_fixture("no-short-hash", &options)?.groups.len(),
+        FIXTURE_GROUPS
+    );
+    Ok(())
+}
+
+// a and b are duplicates. a is deleted while b is being hashed, so it is gone
+// by the time it would be hashed again to compare with c.
+#[test]
+fn files_deleted_during_the_scan_are_skipped() -> io::Result<()> {
+    let root = TempDir::new("vanished");
+    for name in ["a", "b", "c"] {
+        fs::write(root.0.join(name), b"vanish\n")?;
+    }
+    let hashed = Arc::new(AtomicUsize::new(0));
+    let vanishing = root.0.join("a");
+    let options = ScanOptions {
+        hasher: HasherFactory::new(move || {
+            if hashed.fetch_add(1, Ordering::Relaxed) == 1 {
+                let _ = fs::remove_file(&vanishing);
+            }
+            Box::new(Fnv1a(0xcbf29ce484222325))
+        }),
+        ..ScanOptions::default()
+    };
+    let scan = scan(&root.0, &options)?;
+    assert!(scan.errors.is_empty());
+    assert_eq!(scan.num_files, 3);
+    Ok(())
+}
+
+// Minimal JPEGs with the same image data but different EXIF segments
+#[test]
+fn strip_exif_matches_jpegs_differing_in_metadata() -> io::Result<()> {
+    let root = TempDir::new("strip-exif");
+    let jpeg = |exif: &[u8]| {
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1, 0, exif.len() as u8 + 2];
+        jpeg.extend_from_slice(exif);
+        jpeg.extend_from_slice(&[0xff, 0xda, 0, 2, 1, 2, 3, 0xff, 0xd9]);
+        jpeg
+    };
+    fs::write(root.0.join("a.jpg"), jpeg(b"Exif 2019"))?;
+    fs::write(root.0.join("b.jpg"), jpeg(b"Exif 2021, Amsterdam"))?;
+    let options = ScanOptions {
+        normalize: Some(Normalizer::strip_exif()),
+        ..ScanOptions::default()
+    };
+    assert_eq!(scan(&root.0, &options)?.groups.len(), 1);
+    Ok(())
+}
+
+// Line endings differ, and the first CR of the CRLF version ends the first
+// 8 KiB buffer of the normalizing reader
+#[test]
+fn normalize_eol_matches_crlf_and_lf_files() -> io::Result<()> {
+    let root = TempDir::new("normalize-eol");
+    fs::write(root.0.join("crlf.txt"), b"a\r\n".repeat(4000))?;
+    fs::write(root.0.join("lf.txt"), b"a\n".repeat(4000))?;
+    let options = ScanOptions {
+        normalize: Some(Normalizer::normalize_eol()),
+        ..ScanOptions::default()
+    };
+    let scan = scan(&root.0, &options)?;
+    assert_eq!(scan.groups.len(), 1);
+    assert_eq!(scan.groups[0].size, 8000);
+    Ok(())
+}
+
+// A caller's own transform, here ignoring case in .log files only
+#[test]
+fn custom_transform_only_applies_to_its_files() -> io::Result<()> {
+    let root = TempDir::new("transform");
+    fs::write(root.0.join("a.log"), b"Disk full\n")?;
+    fs::write(root.0.join("b.log"), b"DISK FULL\n")?;
+    fs::write(root.0.join("c.dat"), b"disk full\n")?;
+    let lowercase = Normalizer::new(|path| {
+        if path.extension() != Some(OsStr::new("log")) {
+            return Ok(None);
+        }
+        let bytes = fs::read(path)?.to_ascii_lowercase();
+        Ok(Some(Box::new(io::Cursor::new(bytes)) as Box<dyn io::Read>))
+    });
+    let options = ScanOptions {
+        normalize: Some(lowercase),
+        ..ScanOptions::default()
+    };
+    let scan = scan(&root.0, &options)?;
+    assert_eq!(
+        group_names(&scan),
+        [[OsStr::new("a.log"), OsStr::new("b.log")]]
+    );
+    Ok(())
+}
+
+// Passing a directory and a subdirectory must not make the files in the
+// subdirectory duplicates of themselves
+#[test]
+fn overlapping_roots_visit_files_once() -> io::Result<()> {
+    let root = TempDir::new("overlapping");
+    let root = &root.0;
+    fs::create_dir_all(root.join("sub"))?;
+    fs::write(root.join("a"), b"only copy\n")?;
+    fs::write(root.join("sub/b"), b"another only copy\n")?;
+    let roots = [root.join("sub"), root.clone(), root.join("sub/../sub")];
+    let scan = find_duplicates(&roots, &ScanOptions::default(), |_| KeeperChoice::Keep(0))?;
+    assert_eq!(scan.num_files, 2);
+    assert!(scan.actions.is_empty());
+    Ok(())
+}
+
+// A report written into the searched directory must not take part
+#[test]
+fn own_output_files_are_skipped() -> io::Result<()> {
+    let root = TempDir::new("skip-files");
+    for name in ["a", "b", "report.json"] {
+        fs::write(root.0.join(name), b"report\n")?;
+    }
+    let options = ScanOptions {
+        skip_files: vec![root.0.join("report.json")],
+        ..ScanOptions::default()
+    };
+    let scan = scan(&root.0, &options)?;
+    assert_eq!(scan.num_files, 2);
+    assert_eq!(group_names(&scan), [[OsStr::new("a"), OsStr::new("b")]]);
+    Ok(())
+}
+
+// A sparse file reads as zeros, like a written one of the same size
+#[test]
+fn sparse_file_matches_zeros() -> io::Result<()> {
+    let root = TempDir::new("sparse");
+    let len = 3 * BLOCK_LEN as u64;
+    fs::File::create(root.0.join("sparse"))?.set_len(len)?;
+    fs::write(root.0.join("zeros"), vec![0; len as usize])?;
+    fs::write(root.0.join("pattern"), pattern(len as usize, 4))?;
+    let scan = scan(&root.0, &ScanOptions::default())?;
+    assert_eq!(
+        group_names(&scan),
+        [[OsStr::new("sparse"), OsStr::new("zeros")]]
+    );
+    Ok(())
+}
+
+// A second run takes every hash that the first one read in full from its
+// checkpoint
+#[test]
+fn resumed_scan_does_not_read_files_again() -> io::Result<()> {
+    let dir = TempDir::new("checkpoint");
+    let root = dir.0.join("tree");
+    create_fixture(&root)?;
+    let checkpoint = dir.0.join("checkpoint");
+    let options = ScanOptions {
+        checkpoint: Some(checkpoint.clone()),
+        ..ScanOptions::default()
+    };
+    let first = scan(&root, &options)?;
+    let options = ScanOptions {
+        since: Some(Arc::new(PriorManifest::read(&checkpoint)?)),
+        ..ScanOptions::default()
+    };
+    let resumed = scan(&root, &options)?;
+    assert!(first.full_hash_reads > 0);
+    assert_eq!(resumed.full_hash_reads, 0);
+    assert_eq!(resumed.reused_hashes, first.full_hash_reads);
+    assert_eq!(resumed.groups.len(), first.groups.len());
+    Ok(())
+}
+
+#[cfg(feature = "decompress")]
+fn gzip(contents: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(contents)?;
+    encoder.finish()
+}
+
+// With --decompress, files are compared by their decompressed size, so that
+// is what --min-size applies to: small.gz is compressed to below the minimum,
+// stored.gz is not compressed at all, and both are reported. The copies of
+// short.gz are below the minimum either way.
+#[cfg(feature = "decompress")]
+#[test]
+fn min_size_applies_to_the_decompressed_size() -> io::Result<()> {
+    let root = TempDir::new("min-size-decompress");
+    let min_size = 10_000;
+    let contents = pattern(20_000, 10);
+    let short = pattern(5_000, 11);
+    let files = [
+        ("small.gz", gzip(&contents, 9)?),
+        ("stored.gz", gzip(&contents, 0)?),
+        ("short.gz", gzip(&short, 9)?),
+        ("short-stored.gz", gzip(&short, 0)?),
+    ];
+    for (name, compressed) in &files {
+        fs::write(root.0.join(name), compressed)?;
+    }
+    assert!(fs::metadata(root.0.join("small.gz"))?.len() < min_size);
+    assert!(fs::metadata(root.0.join("stored.gz"))?.len() > min_size);
+    let options = ScanOptions {
+        min_size,
+        decompress: true,
+        ..ScanOptions::default()
+    };
+    let scan = scan(&root.0, &options)?;
+    assert_eq!(
+        group_names(&scan),
+        [[OsStr::new("small.gz"), OsStr::new("stored.gz")]]
+    );
+    assert_eq!(scan.groups[0].size, contents.len() as u64);
+    Ok(())
+}
+
+// The trailer of b.gz records the size of its last member only, which equals
+// that of a.gz, yet b.gz holds the contents of a.gz twice
+#[cfg(feature = "decompress")]
+#[test]
+fn decompress_compares_all_gzip_members() -> io::Result<()> {
+    let root = TempDir::new("decompress");
+    let contents = pattern(1000, 5);
+    let member = gzip(&contents, 6)?;
+    fs::write(root.0.join("a.gz"), &member)?;
+    fs::write(root.0.join("b.gz"), [&member[..], &member[..]].concat())?;
+    fs::write(
+        root.0.join("c.gz"),
+        gzip(&[&contents[..], &contents[..]].concat(), 6)?,
+    )?;
+    let options = ScanOptions {
+        decompress: true,
+        ..ScanOptions::default()
+    };
+    let scan = scan(&root.0, &options)?;
+    assert_eq!(
+        group_names(&scan),
+        [[OsStr::new("b.gz"), OsStr::new("c.gz")]]
+    );
+    assert_eq!(scan.groups[0].size, 2000);
+    Ok(())
+}
+
+// The files only differ in their last bytes, so their hashes in a checkpoint
+// written with ignore_trailing match, and must not be used by a scan that
+// compares all bytes
+#[test]
+fn hashes_from_other_settings_are_not_reused() -> io::Result<()> {
+    let dir = TempDir::new("settings");
+    let root = dir.0.join("tree");
+    fs::create_dir(&root)?;
+    let contents = pattern(3 * BLOCK_LEN, 6);
+    fs::write(root.join("a"), [&contents[..], b"footer 1"].concat())?;
+    fs::write(root.join("b"), [&contents[..], b"footer 2"].concat())?;
+    let checkpoint = dir.0.join("checkpoint");
+    let trailing = ScanOptions {
+        ignore_trailing: 8,
+        checkpoint: Some(checkpoint.clone()),
+        ..ScanOptions::default()
+    };
+    assert_eq!(scan(&root, &trailing)?.groups.len(), 1);
+
+    let manifest = PriorManifest::read(&checkpoint)?;
+    assert!(manifest.matches(&trailing));
+    assert!(!manifest.matches(&ScanOptions::default()));
+    let options = ScanOptions {
+        since: Some(Arc::new(manifest)),
+        checkpoint: Some(checkpoint.clone()),
+        ..ScanOptions::default()
+    };
+    let full = scan(&root, &options)?;
+    assert!(full.groups.is_empty());
+    assert_eq!(full.reused_hashes, 0);
+    // The checkpoint now only holds hashes of whole files
+    assert!(PriorManifest::read(&checkpoint)?.matches(&ScanOptions::default()));
+    Ok(())
+}
+
+// Names with a line break or bytes that are not UTF-8 must survive the
+// one-line-per-file format of the checkpoint
+#[test]
+fn checkpoint_keeps_unusual_names() -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = TempDir::new("checkpoint-names");
+    let root = dir.0.join("tree");
+    fs::create_dir(&root)?;
+    for name in [&b"two\nlines"[..], b"caf\xe9", b"back\\slash"] {
+        fs::write(
+            root.join(OsStr::from_bytes(name)),
+            pattern(5 * BLOCK_LEN, 7),
+        )?;
+    }
+    let checkpoint = dir.0.join("checkpoint");
+    let options = ScanOptions {
+        checkpoint: Some(checkpoint.clone()),
+        no_short_hash: true,
+        ..ScanOptions::default()
+    };
+    let first = scan(&root, &options)?;
+    let options = ScanOptions {
+        since: Some(Arc::new(PriorManifest::read(&checkpoint)?)),
+        ..ScanOptions::default()
+    };
+    let resumed = scan(&root, &options)?;
+    assert_eq!(first.full_hash_reads, 3);
+    assert_eq!(resumed.full_hash_reads, 0);
+    assert_eq!(resumed.reused_hashes, 3);
+    assert_eq!(resumed.groups[0].paths.len(), 3);
+    Ok(())
+}
+
+// Directories that only differ in files left out by the filters are
+// identical
+#[test]
+fn dirs_leave_out_excluded_files() -> io::Result<()> {
+    let root = TempDir::new("dirs-exclude");
+    for dir in ["a", "b"] {
+        fs::create_dir(root.0.join(dir))?;
+        fs::write(root.0.join(dir).join("data"), pattern(1000, 8))?;
+    }
+    fs::write(root.0.join("a/notes.tmp"), b"only in a\n")?;
+    let roots = std::slice::from_ref(&root.0);
+    assert!(find_duplicate_dirs(roots, &ScanOptions::default())?.is_empty());
+    let options = ScanOptions {
+        exclude: vec!["*.tmp".to_string()],
+        ..ScanOptions::default()
+    };
+    let groups = find_duplicate_dirs(roots, &options)?;
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].paths, [root.0.join("a"), root.0.join("b")]);
+    Ok(())
+}
+
+// A root inside another root is walked once, as in a scan for duplicates
+#[test]
+fn manifest_lists_files_of_overlapping_roots_once() -> io::Result<()> {
+    let root = TempDir::new("manifest-roots");
+    create_fixture(&root.0)?;
+    let roots = [root.0.join("big"), root.0.clone()];
+    let mut manifest = Vec::new();
+    let num_files = write_hash_manifest(
+        &roots,
+        &ScanOptions::default(),
+        ManifestFormat::Sha256sum,
+        &mut manifest,
+    )?;
+    // Every file of the fixture but the empty ones
+    assert_eq!(num_files, 9);
+    assert_eq!(
+        manifest
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .count(),
+        9
+    );
+    Ok(())
+}
+
+// The archive in the nested root is reported once
+#[cfg(feature = "archive")]
+#[test]
+fn archives_in_overlapping_roots_are_read_once() -> io::Result<()> {
+    let root = TempDir::new("archive-roots");
+    fs::create_dir(root.0.join("sub"))?;
+    let mut builder = tar::Builder::new(fs::File::create(root.0.join("sub/a.tar"))?);
+    for name in ["one", "two"] {
+        let contents = pattern(1000, 9);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, &contents[..])?;
+    }
+    builder.finish()?;
+    let roots = [root.0.join("sub"), root.0.clone()];
+    let archives = dedup::archive::find_archive_duplicates(&roots, &ScanOptions::default())?;
+    assert_eq!(archives.len(), 1);
+    assert_eq!(archives[0].groups[0].paths.len(), 2);
+    Ok(())
+}